@@ -0,0 +1,68 @@
+use anyhow::anyhow;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Device registry URL
+    pub device_registry: Option<String>,
+    /// Kubernetes namespace
+    pub namespace: Option<String>,
+    /// Port for API and health endpoint
+    pub port: u16,
+    /// Applications that can have builds triggered
+    pub allowed_applications: Vec<String>,
+    /// Tekton pipeline used for builds
+    pub pipeline: String,
+    /// Service account used by pipeline runs
+    pub service_account: String,
+    /// Size of the build workspace volume
+    pub volume_size: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            device_registry: None,
+            namespace: None,
+            port: 8080,
+            allowed_applications: Vec::new(),
+            pipeline: "oci-firmware".to_string(),
+            service_account: "pipeline".to_string(),
+            volume_size: "10Gi".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Validate the configuration, reporting all problems found at once.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let mut errors = Vec::new();
+        match &self.device_registry {
+            None => errors.push("device_registry is required".to_string()),
+            Some(url) => {
+                if let Err(e) = reqwest::Url::parse(url) {
+                    errors.push(format!("device_registry '{}' is invalid: {}", url, e));
+                }
+            }
+        }
+        if self.namespace.is_none() {
+            errors.push("namespace is required".to_string());
+        }
+        for (key, value) in [
+            ("pipeline", &self.pipeline),
+            ("service_account", &self.service_account),
+            ("volume_size", &self.volume_size),
+        ] {
+            if value.is_empty() {
+                errors.push(format!("{} must not be empty", key));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("Invalid configuration:\n  {}", errors.join("\n  ")))
+        }
+    }
+}
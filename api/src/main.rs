@@ -22,30 +22,54 @@ use reqwest::Url;
 use serde_json::json;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
 
+mod config;
+
 #[derive(Parser, Debug)]
 struct Args {
+    /// Configuration file (.toml, .yaml or .yml), command line arguments override its values
+    #[clap(long)]
+    config: Option<PathBuf>,
+
     /// Device registry URL
-    /// Mqtt server uri (tcp://host:port)
     #[clap(long)]
-    device_registry: String,
+    device_registry: Option<String>,
 
     /// Kubernetes namespace
     #[clap(long)]
-    namespace: String,
+    namespace: Option<String>,
 
-    /// Port for health endpoint
-    #[clap(long, default_value_t = 8080)]
-    port: u16,
+    /// Port for health endpoint (default 8080)
+    #[clap(long)]
+    port: Option<u16>,
 
     /// A comma-separated list of applications that can have builds triggered
     #[clap(long)]
     allowed_applications: Option<String>,
 }
 
+impl Args {
+    /// Apply command line arguments on top of the configuration file and environment.
+    fn apply(self, config: &mut config::Config) {
+        if self.device_registry.is_some() {
+            config.device_registry = self.device_registry;
+        }
+        if self.namespace.is_some() {
+            config.namespace = self.namespace;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(apps) = self.allowed_applications {
+            config.allowed_applications = apps.split(",").map(|s| s.to_string()).collect();
+        }
+    }
+}
+
 pub struct ApiConfig {
     apps: HashSet<String>,
     namespace: String,
@@ -58,13 +82,15 @@ pub struct ApiConfig {
 async fn main() -> Result<(), anyhow::Error> {
     env_logger::init();
     let args = Args::parse();
-    let namespace = args.namespace;
-    let registry_url = reqwest::Url::parse(&args.device_registry).unwrap();
-    let apps: HashSet<String> = if let Some(apps) = args.allowed_applications {
-        apps.split(",").map(|s| s.to_string()).collect()
-    } else {
-        HashSet::new()
-    };
+    let mut config: config::Config =
+        ajour_schema::config::load(args.config.as_deref(), "AJOUR_API")?;
+    args.apply(&mut config);
+    config.validate()?;
+
+    let namespace = config.namespace.unwrap_or_default();
+    let registry_url = reqwest::Url::parse(&config.device_registry.unwrap_or_default())?;
+    let apps: HashSet<String> = config.allowed_applications.into_iter().collect();
+    let port = config.port;
 
     const GROUP_TEKTON_DEV: &str = "tekton.dev";
     const KIND_PIPELINE_RUN: &str = "PipelineRun";
@@ -79,9 +105,9 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let config = ApiConfig {
         apps,
-        service_account: "pipeline".to_string(),
-        pipeline: "oci-firmware".to_string(),
-        volume_size: "10Gi".to_string(),
+        service_account: config.service_account,
+        pipeline: config.pipeline,
+        volume_size: config.volume_size,
         namespace,
     };
 
@@ -109,7 +135,7 @@ async fn main() -> Result<(), anyhow::Error> {
                     ),
             )
     })
-    .bind(("0.0.0.0", args.port))?
+    .bind(("0.0.0.0", port))?
     .run()
     .await?;
     Ok(())
@@ -185,6 +211,8 @@ async fn trigger_build(
             image,
             image_pull_policy: _,
             build,
+            registry: _,
+            poll_interval: _,
         } => {
             if state.is_allowed(app) {
                 if let Some(build) = build {
@@ -413,7 +441,7 @@ impl State {
         }
 
         // Delete existing if exists
-        if let Ok(_) = self.kube.delete(&name, &Default::default()).await {
+        if self.kube.delete(&name, &Default::default()).await.is_ok() {
             log::info!("Deleted previous pipeline run {}", name);
         }
 
@@ -440,7 +468,7 @@ impl State {
             },
             FirmwareBuildEnv {
                 name: "BUILD_ARGS".to_string(),
-                value: BuildEnvArgValue::Array(spec.args.unwrap_or(Vec::new())),
+                value: BuildEnvArgValue::Array(spec.args.unwrap_or_default()),
             },
             FirmwareBuildEnv {
                 name: "ARTIFACT_PATH".to_string(),
@@ -486,9 +514,11 @@ fn has_build_spec(spec: &Option<Result<FirmwareSpec, serde_json::Error>>) -> boo
         image: _,
         image_pull_policy: _,
         build,
+        registry: _,
+        poll_interval: _,
     })) = spec
     {
         return build.is_some();
     }
-    return false;
+    false
 }
@@ -31,9 +31,15 @@ impl From<&Device> for DeviceModel {
                     image: _,
                     image_pull_policy: _,
                     build,
+                    registry: _,
+                    poll_interval: _,
                 } => ("Container".to_string(), build.is_some()),
                 FirmwareSpec::HAWKBIT { .. } => ("Hawkbit".to_string(), false),
                 FirmwareSpec::FILE { .. } => ("File".to_string(), false),
+                FirmwareSpec::HTTP { .. } => ("HTTP".to_string(), false),
+                FirmwareSpec::S3 { .. } => ("S3".to_string(), false),
+                FirmwareSpec::CHANNEL { .. } => ("Channel".to_string(), false),
+                FirmwareSpec::COMPONENTS(_) => ("Components".to_string(), false),
             }
         } else {
             ("Unspecified".to_string(), false)
@@ -217,9 +223,15 @@ impl From<&(Application, Vec<Device>)> for ApplicationModel {
                     image: _,
                     image_pull_policy: _,
                     build,
+                    registry: _,
+                    poll_interval: _,
                 } => ("Container".to_string(), build.is_some()),
                 FirmwareSpec::HAWKBIT { .. } => ("Hawkbit".to_string(), false),
                 FirmwareSpec::FILE { .. } => ("File".to_string(), false),
+                FirmwareSpec::HTTP { .. } => ("HTTP".to_string(), false),
+                FirmwareSpec::S3 { .. } => ("S3".to_string(), false),
+                FirmwareSpec::CHANNEL { .. } => ("Channel".to_string(), false),
+                FirmwareSpec::COMPONENTS(_) => ("Components".to_string(), false),
             }
         } else {
             ("Unspecified".to_string(), false)
@@ -0,0 +1,117 @@
+use anyhow::{anyhow, Context};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Load configuration from an optional TOML or YAML file, applying environment variable overrides.
+///
+/// The file format is selected by the file extension (`.toml`, `.yaml` or `.yml`). Environment
+/// variables named `<prefix>__<key>__<key>` override individual values, keys are lower cased. For
+/// example, `AJOUR__MQTT__URI` overrides the `uri` value of the `mqtt` section. Values are parsed as
+/// YAML scalars, so a string that looks like a number or a boolean must be quoted.
+pub fn load<T: DeserializeOwned>(path: Option<&Path>, prefix: &str) -> Result<T, anyhow::Error> {
+    let mut value = if let Some(path) = path {
+        read(path).with_context(|| format!("Error reading configuration file {:?}", path))?
+    } else {
+        Value::Object(Map::new())
+    };
+    apply_env(&mut value, prefix, std::env::vars())?;
+    serde_json::from_value(value).map_err(|e| anyhow!("Invalid configuration: {}", e))
+}
+
+fn read(path: &Path) -> Result<Value, anyhow::Error> {
+    let data = std::fs::read_to_string(path)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => Ok(toml::from_str(&data)?),
+        Some("yaml") | Some("yml") => Ok(serde_yaml::from_str(&data)?),
+        _ => Err(anyhow!(
+            "Unknown configuration file format, expected .toml, .yaml or .yml"
+        )),
+    }
+}
+
+fn apply_env<I>(value: &mut Value, prefix: &str, vars: I) -> Result<(), anyhow::Error>
+where
+    I: Iterator<Item = (String, String)>,
+{
+    let prefix = format!("{}__", prefix);
+    for (key, v) in vars {
+        if let Some(path) = key.strip_prefix(&prefix) {
+            let path: Vec<String> = path.split("__").map(|s| s.to_lowercase()).collect();
+            let v: Value = serde_yaml::from_str(&v).unwrap_or(Value::String(v));
+            set(value, &path, v).with_context(|| format!("Error applying {}", key))?;
+        }
+    }
+    Ok(())
+}
+
+fn set(target: &mut Value, path: &[String], v: Value) -> Result<(), anyhow::Error> {
+    match path.split_first() {
+        None => {
+            *target = v;
+            Ok(())
+        }
+        Some((key, rest)) => {
+            if target.is_null() {
+                *target = Value::Object(Map::new());
+            }
+            let map = target
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("'{}' is not a section", key))?;
+            set(map.entry(key.clone()).or_insert(Value::Null), rest, v)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn env_overrides() {
+        let mut value: Value = toml::from_str(
+            r#"
+            [mqtt]
+            uri = "tcp://localhost:1883"
+
+            [stores.oci.default]
+            prefix = "quay.io/"
+            "#,
+        )
+        .unwrap();
+
+        let vars = vec![
+            (
+                "AJOUR__MQTT__URI".to_string(),
+                "ssl://mqtt:8883".to_string(),
+            ),
+            (
+                "AJOUR__STORES__OCI__DEFAULT__TLS".to_string(),
+                "true".to_string(),
+            ),
+            ("AJOUR__HEALTH__PORT".to_string(), "9090".to_string()),
+            ("OTHER__MQTT__URI".to_string(), "ignored".to_string()),
+        ];
+        apply_env(&mut value, "AJOUR", vars.into_iter()).unwrap();
+
+        assert_eq!(
+            json!({
+                "mqtt": { "uri": "ssl://mqtt:8883" },
+                "stores": { "oci": { "default": { "prefix": "quay.io/", "tls": true } } },
+                "health": { "port": 9090 },
+            }),
+            value
+        );
+    }
+
+    #[test]
+    fn env_override_not_a_section() {
+        let mut value = json!({ "mqtt": "tcp://localhost:1883" });
+        let vars = vec![(
+            "AJOUR__MQTT__URI".to_string(),
+            "ssl://mqtt:8883".to_string(),
+        )];
+        assert!(apply_env(&mut value, "AJOUR", vars.into_iter()).is_err());
+    }
+}
@@ -1,22 +1,24 @@
 use chrono::{DateTime, Utc};
 use drogue_client::{core::v1::Conditions, dialect, Section};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[cfg(feature = "config")]
+pub mod config;
+mod selector;
+
+pub use selector::LabelSelector;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
 pub enum ImagePullPolicy {
     Always,
+    #[default]
     IfNotPresent,
 }
 
-impl Default for ImagePullPolicy {
-    fn default() -> Self {
-        Self::IfNotPresent
-    }
-}
-
 dialect!(FirmwareSpec [Section::Spec => "firmware"]);
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum FirmwareSpec {
     #[serde(rename = "container")]
     OCI {
@@ -25,14 +27,134 @@ pub enum FirmwareSpec {
         image_pull_policy: ImagePullPolicy,
         #[serde(skip_serializing_if = "Option::is_none")]
         build: Option<FirmwareBuildSpec>,
+        /// Registry prefix, overriding the prefix configured for the update server
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        registry: Option<String>,
+        /// Poll interval in seconds, see [`FirmwareSpec::poll_interval`]
+        #[serde(
+            rename = "pollInterval",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        poll_interval: Option<u32>,
     },
     #[serde(rename = "hawkbit")]
-    HAWKBIT { controller: String },
+    HAWKBIT {
+        controller: String,
+        /// Hawkbit instance, overriding the instance configured for the update server
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
+        /// Tenant, overriding the tenant configured for the update server
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+        /// Secret providing the `gateway-token` of the tenant or `target-token` of the controller
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secret: Option<String>,
+        /// Controller attributes, mapping attribute names to `label:<name>`, `annotation:<name>` or
+        /// `version` (the firmware version reported by the device)
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        attributes: HashMap<String, String>,
+        /// Software module type of the deployment chunk to use
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        part: Option<String>,
+        /// File name of the artifact to use
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        artifact: Option<String>,
+        /// Poll interval in seconds, see [`FirmwareSpec::poll_interval`]
+        #[serde(
+            rename = "pollInterval",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        poll_interval: Option<u32>,
+    },
     #[serde(rename = "file")]
-    FILE { name: String },
+    FILE {
+        name: String,
+        /// Version of a versioned firmware, or `latest` for the highest version
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<String>,
+        /// Poll interval in seconds, see [`FirmwareSpec::poll_interval`]
+        #[serde(
+            rename = "pollInterval",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        poll_interval: Option<u32>,
+    },
+    #[serde(rename = "http")]
+    HTTP {
+        /// URL of the firmware metadata (JSON with version, checksum and size)
+        metadata: String,
+        /// URL of the firmware binary
+        binary: String,
+        /// Secret providing request headers, such as Authorization
+        #[serde(skip_serializing_if = "Option::is_none")]
+        secret: Option<String>,
+        /// Poll interval in seconds, see [`FirmwareSpec::poll_interval`]
+        #[serde(
+            rename = "pollInterval",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        poll_interval: Option<u32>,
+    },
+    /// Firmware stored as `<name>.json` and `<name>.bin` in an S3 bucket
+    #[serde(rename = "s3")]
+    S3 {
+        name: String,
+        /// Poll interval in seconds, see [`FirmwareSpec::poll_interval`]
+        #[serde(
+            rename = "pollInterval",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        poll_interval: Option<u32>,
+    },
+    /// Firmware of a named release channel defined by the application
+    #[serde(rename = "channel")]
+    CHANNEL {
+        name: String,
+        /// Poll interval in seconds, see [`FirmwareSpec::poll_interval`]
+        #[serde(
+            rename = "pollInterval",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        poll_interval: Option<u32>,
+    },
+    /// Firmware for multiple components of a device, updated in the order listed
+    #[serde(rename = "components")]
+    COMPONENTS(Vec<FirmwareComponent>),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl FirmwareSpec {
+    /// Poll interval in seconds sent to devices that are in sync or asked to wait.
+    ///
+    /// A spec referring to a channel can set its own interval, taking precedence over the interval
+    /// of the channel.
+    pub fn poll_interval(&self) -> Option<u32> {
+        match self {
+            Self::OCI { poll_interval, .. }
+            | Self::HAWKBIT { poll_interval, .. }
+            | Self::FILE { poll_interval, .. }
+            | Self::HTTP { poll_interval, .. }
+            | Self::S3 { poll_interval, .. }
+            | Self::CHANNEL { poll_interval, .. } => *poll_interval,
+            Self::COMPONENTS(_) => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FirmwareComponent {
+    /// Component name, as reported by the device
+    pub name: String,
+    /// Firmware for the component
+    pub firmware: FirmwareSpec,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FirmwareBuildSpec {
     /// Build source
     pub source: FirmwareBuildSource,
@@ -51,13 +173,13 @@ pub struct FirmwareBuildSpec {
     pub timeout: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FirmwareBuildEnv {
     pub name: String,
     pub value: BuildEnvArgValue,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum BuildEnvArgValue {
     #[serde(rename = "git")]
@@ -65,7 +187,7 @@ pub enum BuildEnvArgValue {
     Array(Vec<String>),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum FirmwareBuildSource {
     #[serde(rename = "git")]
     GIT {
@@ -75,19 +197,54 @@ pub enum FirmwareBuildSource {
     },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FirmwareBuildArtifact {
     /// Path to firmware artifact relative to project directory
     pub path: String,
 }
 
+dialect!(FirmwareRules [Section::Spec => "firmwareRules"]);
+
+/// Ordered list of firmware rules for an application, the first rule matching a device is used.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct FirmwareRules(pub Vec<FirmwareRule>);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FirmwareRule {
+    /// Label selector matching devices
+    pub selector: LabelSelector,
+    /// Firmware for matching devices
+    pub firmware: FirmwareSpec,
+}
+
+impl FirmwareRules {
+    /// Find the firmware of the first rule matching the labels.
+    pub fn select(&self, labels: &HashMap<String, String>) -> Option<&FirmwareSpec> {
+        self.0
+            .iter()
+            .find(|rule| rule.selector.matches(labels))
+            .map(|rule| &rule.firmware)
+    }
+}
+
+dialect!(FirmwareChannels [Section::Spec => "firmwareChannels"]);
+
+/// Named release channels of an application, such as `stable`, `beta` or `nightly`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct FirmwareChannels(pub HashMap<String, FirmwareSpec>);
+
 dialect!(FirmwareStatus [Section::Status => "firmware"]);
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct FirmwareStatus {
     pub conditions: Conditions,
     pub current: String,
     pub target: String,
+    /// Status of individual components, for devices with multiple components
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub components: HashMap<String, FirmwareStatus>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -122,4 +279,81 @@ mod tests {
         let output = serde_json::to_string(&args).unwrap();
         assert_eq!("[{\"name\":\"key1\",\"value\":\"mystring\"},{\"name\":\"key2\",\"value\":[\"elem1\",\"elem2\"]}]", output);
     }
+
+    #[test]
+    fn rules_select() {
+        let rules: FirmwareRules = serde_json::from_str(
+            r#"[
+                {"selector": "region=eu,tier=beta", "firmware": {"file": {"name": "beta"}}},
+                {"selector": "region=eu", "firmware": {"file": {"name": "stable"}}}
+            ]"#,
+        )
+        .unwrap();
+
+        let labels = |l: &[(&str, &str)]| -> HashMap<String, String> {
+            l.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let name = |spec: Option<&FirmwareSpec>| match spec {
+            Some(FirmwareSpec::FILE { name, .. }) => Some(name.clone()),
+            _ => None,
+        };
+
+        assert_eq!(
+            Some("beta".to_string()),
+            name(rules.select(&labels(&[("region", "eu"), ("tier", "beta")])))
+        );
+        assert_eq!(
+            Some("stable".to_string()),
+            name(rules.select(&labels(&[("region", "eu")])))
+        );
+        assert_eq!(None, name(rules.select(&labels(&[("region", "us")]))));
+    }
+
+    #[test]
+    fn channels_serde() {
+        let channels: FirmwareChannels = serde_json::from_str(
+            r#"{
+                "stable": {"file": {"name": "app", "version": "1.0.0", "pollInterval": 3600}},
+                "beta": {"hawkbit": {"controller": "beta"}}
+            }"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            channels.0.get("stable"),
+            Some(FirmwareSpec::FILE { version: Some(v), .. }) if v == "1.0.0"
+        ));
+        assert_eq!(Some(3600), channels.0["stable"].poll_interval());
+        assert!(matches!(
+            channels.0.get("beta"),
+            Some(FirmwareSpec::HAWKBIT { controller, .. }) if controller == "beta"
+        ));
+
+        let spec: FirmwareSpec = serde_json::from_str(r#"{"channel": {"name": "beta"}}"#).unwrap();
+        assert!(matches!(&spec, FirmwareSpec::CHANNEL { name, .. } if name == "beta"));
+        assert_eq!(None, spec.poll_interval());
+    }
+
+    #[test]
+    fn components_serde() {
+        let spec: FirmwareSpec = serde_json::from_str(
+            r#"{"components": [
+                {"name": "modem", "firmware": {"file": {"name": "modem"}}},
+                {"name": "app", "firmware": {"channel": {"name": "stable"}}}
+            ]}"#,
+        )
+        .unwrap();
+        match spec {
+            FirmwareSpec::COMPONENTS(components) => {
+                let names: Vec<&str> = components.iter().map(|c| c.name.as_str()).collect();
+                assert_eq!(vec!["modem", "app"], names);
+                assert!(matches!(
+                    &components[1].firmware,
+                    FirmwareSpec::CHANNEL { name, .. } if name == "stable"
+                ));
+            }
+            spec => panic!("Unexpected firmware {:?}", spec),
+        }
+    }
 }
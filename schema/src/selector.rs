@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// A label selector such as `region=eu,tier!=beta,canary`, where all requirements must match.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub struct LabelSelector {
+    requirements: Vec<Requirement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+}
+
+impl LabelSelector {
+    pub fn parse(selector: &str) -> Result<Self, String> {
+        let mut requirements = Vec::new();
+        for r in selector
+            .split(',')
+            .map(|r| r.trim())
+            .filter(|r| !r.is_empty())
+        {
+            let requirement = if let Some((k, v)) = r.split_once("!=") {
+                Requirement::NotEquals(key(k)?, v.trim().to_string())
+            } else if let Some((k, v)) = r.split_once("==") {
+                Requirement::Equals(key(k)?, v.trim().to_string())
+            } else if let Some((k, v)) = r.split_once('=') {
+                Requirement::Equals(key(k)?, v.trim().to_string())
+            } else if let Some(k) = r.strip_prefix('!') {
+                Requirement::NotExists(key(k)?)
+            } else {
+                Requirement::Exists(key(r)?)
+            };
+            requirements.push(requirement);
+        }
+        Ok(Self { requirements })
+    }
+
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.requirements.iter().all(|r| match r {
+            Requirement::Equals(k, v) => labels.get(k) == Some(v),
+            Requirement::NotEquals(k, v) => labels.get(k) != Some(v),
+            Requirement::Exists(k) => labels.contains_key(k),
+            Requirement::NotExists(k) => !labels.contains_key(k),
+        })
+    }
+}
+
+fn key(k: &str) -> Result<String, String> {
+    let k = k.trim();
+    if k.is_empty() || k.contains(|c: char| c.is_whitespace() || c == '=' || c == '!') {
+        Err(format!("Invalid label selector key '{}'", k))
+    } else {
+        Ok(k.to_string())
+    }
+}
+
+impl fmt::Display for LabelSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let requirements: Vec<String> = self
+            .requirements
+            .iter()
+            .map(|r| match r {
+                Requirement::Equals(k, v) => format!("{}={}", k, v),
+                Requirement::NotEquals(k, v) => format!("{}!={}", k, v),
+                Requirement::Exists(k) => k.clone(),
+                Requirement::NotExists(k) => format!("!{}", k),
+            })
+            .collect();
+        write!(f, "{}", requirements.join(","))
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = String;
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        Self::parse(selector)
+    }
+}
+
+impl TryFrom<String> for LabelSelector {
+    type Error = String;
+    fn try_from(selector: String) -> Result<Self, Self::Error> {
+        Self::parse(&selector)
+    }
+}
+
+impl From<LabelSelector> for String {
+    fn from(selector: LabelSelector) -> Self {
+        selector.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(l: &[(&str, &str)]) -> HashMap<String, String> {
+        l.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn selector_matches() {
+        let selector = LabelSelector::parse("region=eu, tier!=beta,canary,!legacy").unwrap();
+        assert!(selector.matches(&labels(&[("region", "eu"), ("canary", "")])));
+        assert!(!selector.matches(&labels(&[("region", "us"), ("canary", "")])));
+        assert!(!selector.matches(&labels(&[
+            ("region", "eu"),
+            ("tier", "beta"),
+            ("canary", "")
+        ])));
+        assert!(!selector.matches(&labels(&[("region", "eu")])));
+        assert!(!selector.matches(&labels(&[
+            ("region", "eu"),
+            ("canary", ""),
+            ("legacy", "1")
+        ])));
+        assert!(LabelSelector::parse("").unwrap().matches(&labels(&[])));
+    }
+
+    #[test]
+    fn selector_serde() {
+        let selector: LabelSelector = serde_json::from_str("\"region==eu,!legacy\"").unwrap();
+        assert_eq!(
+            "\"region=eu,!legacy\"",
+            serde_json::to_string(&selector).unwrap()
+        );
+        assert!(serde_json::from_str::<LabelSelector>("\"=eu\"").is_err());
+    }
+}
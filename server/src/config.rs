@@ -0,0 +1,630 @@
+use ajour_schema::LabelSelector;
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::cosign::SignaturePolicy;
+use crate::telemetry::TracingExporter;
+
+/// Name of the store used when an application does not select one.
+pub const DEFAULT_STORE: &str = "default";
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub mqtt: MqttConfig,
+    pub registry: RegistryConfig,
+    pub health: HealthConfig,
+    pub status: StatusConfig,
+    pub retry: RetryConfig,
+    pub polling: PollingConfig,
+    pub tracing: TracingConfig,
+    pub stores: StoresConfig,
+    pub applications: ApplicationsConfig,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Mqtt server uri (tcp://host:port)
+    pub uri: Option<String>,
+    /// Mqtt group id for shared subscription (for horizontal scaling)
+    pub group_id: Option<String>,
+    /// Disable TLS
+    pub disable_tls: bool,
+    /// Ignore cert validation
+    pub insecure_tls: bool,
+    /// Path to CA
+    pub ca_path: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// Device registry URL
+    pub url: Option<String>,
+    /// User for authenticating ajour to Drogue IoT
+    pub user: Option<String>,
+    /// Token for authenticating ajour to Drogue IoT
+    pub token: Option<String>,
+    /// Time in seconds to cache application and device objects, 0 to disable
+    pub cache_ttl: u64,
+    /// Max number of cached application and device objects
+    pub cache_entries_max: usize,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            user: None,
+            token: None,
+            cache_ttl: 30,
+            cache_entries_max: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub disable: bool,
+    pub port: u16,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            disable: false,
+            port: 8080,
+        }
+    }
+}
+
+/// Controls how often firmware status is written to the device registry.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusConfig {
+    /// Write update progress when crossing a multiple of this many percent, and on completion
+    pub progress_step: f32,
+    /// Write update progress at least this often, in seconds
+    pub progress_interval: u64,
+    /// Number of retries when a status write conflicts with another change
+    pub conflict_retries: usize,
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        Self {
+            progress_step: 10.0,
+            progress_interval: 30,
+            conflict_retries: 3,
+        }
+    }
+}
+
+/// Controls how long devices are asked to wait after errors processing their status.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Wait after the first error, in seconds, doubled for every consecutive error
+    pub initial_backoff: u64,
+    /// Maximum wait after errors, in seconds
+    pub max_backoff: u64,
+    /// Number of consecutive errors, or failed installations of a version, before the update of a
+    /// device is marked as failed
+    pub budget: u32,
+    /// Max number of devices to keep errors and ongoing updates of, dropping the least recent ones
+    pub devices_max: usize,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: 5,
+            max_backoff: 600,
+            budget: 10,
+            devices_max: 10000,
+        }
+    }
+}
+
+/// Controls how often devices are asked to check in.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollingConfig {
+    /// Poll interval in seconds sent to devices, unless set for the application or device
+    pub interval: Option<u32>,
+    /// Fraction of the poll interval randomly added or subtracted
+    pub jitter: f32,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            jitter: 0.1,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    pub exporter: TracingExporter,
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            exporter: TracingExporter::None,
+            otlp_endpoint: None,
+        }
+    }
+}
+
+/// Named firmware stores, applications use the store named `default` unless overridden.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoresConfig {
+    pub oci: HashMap<String, OciStoreConfig>,
+    pub hawkbit: HashMap<String, HawkbitStoreConfig>,
+    pub file: HashMap<String, FileStoreConfig>,
+    pub http: HashMap<String, HttpStoreConfig>,
+    pub s3: HashMap<String, S3StoreConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OciStoreConfig {
+    /// Prefix to use for container registry storing images
+    pub prefix: Option<String>,
+    /// Use TLS when talking to the registry
+    pub tls: bool,
+    /// User to use for authenticating to registry
+    pub user: Option<String>,
+    /// Token to use for authenticating to registry
+    pub token: Option<String>,
+    /// File holding the token, re-read when modified
+    pub token_file: Option<PathBuf>,
+    /// Docker config file with credentials by registry, such as a mounted pull secret
+    pub docker_config: Option<PathBuf>,
+    /// Interval in seconds after which bearer tokens are refreshed
+    pub token_refresh: u64,
+    /// Do not require registry to be valid cert and host
+    pub insecure: bool,
+    /// Max number of firmware cache entries
+    pub cache_entries_max: usize,
+    /// Metadata cache expiry in seconds
+    pub cache_expiry: Option<u64>,
+    /// Public keys (PEM) accepted for cosign image signatures
+    pub public_keys: Vec<PathBuf>,
+    /// Signature policy for applications not setting their own
+    pub signature_policy: SignaturePolicy,
+    /// Device label selecting the manifest of multi-platform images
+    pub platform_label: String,
+}
+
+impl Default for OciStoreConfig {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            tls: false,
+            user: None,
+            token: None,
+            token_file: None,
+            docker_config: None,
+            token_refresh: 240,
+            insecure: false,
+            cache_entries_max: 50,
+            cache_expiry: None,
+            public_keys: Vec::new(),
+            signature_policy: SignaturePolicy::Off,
+            platform_label: "ajour.drogue.io/platform".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HawkbitStoreConfig {
+    pub url: Option<String>,
+    pub tenant: Option<String>,
+    pub gateway_token: Option<String>,
+    /// Directory of secrets referenced by firmware specs, for other tenants and target tokens
+    pub secrets_path: Option<PathBuf>,
+    /// URLs of other Hawkbit instances firmware specs may point to
+    pub instances: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileStoreConfig {
+    pub path: Option<PathBuf>,
+    /// Derive checksum, size and version from the firmware when no metadata file exists
+    pub auto_metadata: bool,
+    /// Max number of firmware files to keep derived metadata of
+    pub cache_entries_max: usize,
+}
+
+impl Default for FileStoreConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            auto_metadata: false,
+            cache_entries_max: 50,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpStoreConfig {
+    /// URL prefixes that firmware may be fetched from, nothing is fetched when empty
+    pub urls: Vec<String>,
+    /// Directory of secrets, each a directory with one file per request header
+    pub secrets_path: Option<PathBuf>,
+    /// URL prefixes that secrets may be sent to
+    pub secret_urls: Vec<String>,
+    /// Max number of metadata and firmware cache entries
+    pub cache_entries_max: usize,
+}
+
+impl Default for HttpStoreConfig {
+    fn default() -> Self {
+        Self {
+            urls: Vec::new(),
+            secrets_path: None,
+            secret_urls: Vec::new(),
+            cache_entries_max: 50,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3StoreConfig {
+    /// Bucket holding firmware
+    pub bucket: Option<String>,
+    /// Prefix of firmware objects within the bucket
+    pub prefix: String,
+    /// Bucket region
+    pub region: String,
+    /// Endpoint of an S3 compatible store such as MinIO, uses AWS if not set
+    pub endpoint: Option<String>,
+    /// Access key, read from AWS_ACCESS_KEY_ID or the AWS profile if not set
+    pub access_key: Option<String>,
+    /// Secret key, read from AWS_SECRET_ACCESS_KEY or the AWS profile if not set
+    pub secret_key: Option<String>,
+    /// Size in bytes of each ranged request when fetching firmware
+    pub range_size: u64,
+    /// Max number of firmware cache entries
+    pub cache_entries_max: usize,
+}
+
+impl Default for S3StoreConfig {
+    fn default() -> Self {
+        Self {
+            bucket: None,
+            prefix: String::new(),
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            access_key: None,
+            secret_key: None,
+            range_size: 1024 * 1024,
+            cache_entries_max: 50,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApplicationsConfig {
+    /// Applications to manage firmware updates for (will use all accessible from service account if empty)
+    pub include: Vec<String>,
+    /// Applications to exclude from ajour processing
+    pub exclude: Vec<String>,
+    /// Only manage applications with labels matching this selector
+    pub selector: Option<LabelSelector>,
+    /// Interval in seconds between checking for new or removed applications, 0 to disable
+    pub discovery_interval: u64,
+    /// Per-application settings
+    pub overrides: HashMap<String, ApplicationConfig>,
+}
+
+impl Default for ApplicationsConfig {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            selector: None,
+            discovery_interval: 60,
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+/// Per-application selection of named stores.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApplicationConfig {
+    pub oci: Option<String>,
+    pub hawkbit: Option<String>,
+    pub file: Option<String>,
+    pub http: Option<String>,
+    pub s3: Option<String>,
+    /// Signature policy for container images, overriding the policy of the store
+    pub signature_policy: Option<SignaturePolicy>,
+}
+
+impl Config {
+    /// Validate the configuration, reporting all problems found at once.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let mut errors = Vec::new();
+
+        if self.mqtt.uri.is_none() {
+            errors.push("mqtt.uri is required".to_string());
+        }
+
+        match &self.registry.url {
+            None => errors.push("registry.url is required".to_string()),
+            Some(url) => {
+                if let Err(e) = reqwest::Url::parse(url) {
+                    errors.push(format!("registry.url '{}' is invalid: {}", url, e));
+                }
+            }
+        }
+        if self.registry.user.is_none() {
+            errors.push("registry.user is required".to_string());
+        }
+        if self.registry.token.is_none() {
+            errors.push("registry.token is required".to_string());
+        }
+        if self.registry.cache_entries_max == 0 {
+            errors.push("registry.cache_entries_max must be greater than 0".to_string());
+        }
+
+        if !(0.0..=100.0).contains(&self.status.progress_step) {
+            errors.push("status.progress_step must be between 0 and 100".to_string());
+        }
+        if self.retry.initial_backoff == 0 {
+            errors.push("retry.initial_backoff must be greater than 0".to_string());
+        }
+        if self.retry.max_backoff < self.retry.initial_backoff {
+            errors
+                .push("retry.max_backoff must not be less than retry.initial_backoff".to_string());
+        }
+        if !(0.0..1.0).contains(&self.polling.jitter) {
+            errors.push("polling.jitter must be at least 0 and less than 1".to_string());
+        }
+        if self.retry.budget == 0 {
+            errors.push("retry.budget must be greater than 0".to_string());
+        }
+        if self.retry.devices_max == 0 {
+            errors.push("retry.devices_max must be greater than 0".to_string());
+        }
+
+        for (name, store) in self.stores.oci.iter() {
+            if store.prefix.is_none() {
+                errors.push(format!("stores.oci.{}.prefix is required", name));
+            }
+            if store.cache_entries_max == 0 {
+                errors.push(format!(
+                    "stores.oci.{}.cache_entries_max must be greater than 0",
+                    name
+                ));
+            }
+            if store.token.is_some() && store.token_file.is_some() {
+                errors.push(format!(
+                    "stores.oci.{}.token and token_file can not both be set",
+                    name
+                ));
+            }
+            for (key, path) in [
+                ("token_file", &store.token_file),
+                ("docker_config", &store.docker_config),
+            ] {
+                if let Some(path) = path {
+                    if !path.is_file() {
+                        errors.push(format!(
+                            "stores.oci.{}.{} {:?} is not a file",
+                            name, key, path
+                        ));
+                    }
+                }
+            }
+            if store.signature_policy != SignaturePolicy::Off && store.public_keys.is_empty() {
+                errors.push(format!(
+                    "stores.oci.{}.public_keys is required to verify signatures",
+                    name
+                ));
+            }
+            for key in store.public_keys.iter() {
+                if !key.is_file() {
+                    errors.push(format!(
+                        "stores.oci.{}.public_keys {:?} is not a file",
+                        name, key
+                    ));
+                }
+            }
+        }
+
+        for (name, store) in self.stores.hawkbit.iter() {
+            match &store.url {
+                None => errors.push(format!("stores.hawkbit.{}.url is required", name)),
+                Some(url) => {
+                    if let Err(e) = reqwest::Url::parse(url) {
+                        errors.push(format!(
+                            "stores.hawkbit.{}.url '{}' is invalid: {}",
+                            name, url, e
+                        ));
+                    }
+                }
+            }
+            if store.tenant.is_none() {
+                errors.push(format!("stores.hawkbit.{}.tenant is required", name));
+            }
+            if store.gateway_token.is_none() && store.secrets_path.is_none() {
+                errors.push(format!(
+                    "stores.hawkbit.{}.gateway_token or secrets_path is required",
+                    name
+                ));
+            }
+            if let Some(path) = &store.secrets_path {
+                if !path.is_dir() {
+                    errors.push(format!(
+                        "stores.hawkbit.{}.secrets_path {:?} is not a directory",
+                        name, path
+                    ));
+                }
+            }
+            for url in store.instances.iter() {
+                if let Err(e) = reqwest::Url::parse(url) {
+                    errors.push(format!(
+                        "stores.hawkbit.{}.instances '{}' is invalid: {}",
+                        name, url, e
+                    ));
+                }
+            }
+        }
+
+        for (name, store) in self.stores.file.iter() {
+            match &store.path {
+                None => errors.push(format!("stores.file.{}.path is required", name)),
+                Some(path) => {
+                    if !path.is_dir() {
+                        errors.push(format!(
+                            "stores.file.{}.path {:?} is not a directory",
+                            name, path
+                        ));
+                    }
+                }
+            }
+            if store.cache_entries_max == 0 {
+                errors.push(format!(
+                    "stores.file.{}.cache_entries_max must be greater than 0",
+                    name
+                ));
+            }
+        }
+
+        for (name, store) in self.stores.http.iter() {
+            if store.urls.is_empty() {
+                errors.push(format!(
+                    "stores.http.{}.urls must list the URL prefixes firmware may be fetched from",
+                    name
+                ));
+            }
+            for url in store.urls.iter() {
+                if let Err(e) = reqwest::Url::parse(url) {
+                    errors.push(format!(
+                        "stores.http.{}.urls '{}' is invalid: {}",
+                        name, url, e
+                    ));
+                }
+            }
+            if let Some(path) = &store.secrets_path {
+                if !path.is_dir() {
+                    errors.push(format!(
+                        "stores.http.{}.secrets_path {:?} is not a directory",
+                        name, path
+                    ));
+                }
+            }
+            for url in store.secret_urls.iter() {
+                if let Err(e) = reqwest::Url::parse(url) {
+                    errors.push(format!(
+                        "stores.http.{}.secret_urls '{}' is invalid: {}",
+                        name, url, e
+                    ));
+                }
+            }
+            if store.cache_entries_max == 0 {
+                errors.push(format!(
+                    "stores.http.{}.cache_entries_max must be greater than 0",
+                    name
+                ));
+            }
+        }
+
+        for (name, store) in self.stores.s3.iter() {
+            if store.bucket.is_none() {
+                errors.push(format!("stores.s3.{}.bucket is required", name));
+            }
+            if let Some(endpoint) = &store.endpoint {
+                if let Err(e) = reqwest::Url::parse(endpoint) {
+                    errors.push(format!(
+                        "stores.s3.{}.endpoint '{}' is invalid: {}",
+                        name, endpoint, e
+                    ));
+                }
+            }
+            if store.access_key.is_some() != store.secret_key.is_some() {
+                errors.push(format!(
+                    "stores.s3.{}.access_key and secret_key must be set together",
+                    name
+                ));
+            }
+            if store.range_size == 0 {
+                errors.push(format!(
+                    "stores.s3.{}.range_size must be greater than 0",
+                    name
+                ));
+            }
+            if store.cache_entries_max == 0 {
+                errors.push(format!(
+                    "stores.s3.{}.cache_entries_max must be greater than 0",
+                    name
+                ));
+            }
+        }
+
+        for app in self.applications.include.iter() {
+            if self.applications.exclude.contains(app) {
+                errors.push(format!(
+                    "application '{}' is both included and excluded",
+                    app
+                ));
+            }
+        }
+
+        for (app, config) in self.applications.overrides.iter() {
+            check_store_ref(&mut errors, app, "oci", &config.oci, &self.stores.oci);
+            check_store_ref(
+                &mut errors,
+                app,
+                "hawkbit",
+                &config.hawkbit,
+                &self.stores.hawkbit,
+            );
+            check_store_ref(&mut errors, app, "file", &config.file, &self.stores.file);
+            check_store_ref(&mut errors, app, "http", &config.http, &self.stores.http);
+            check_store_ref(&mut errors, app, "s3", &config.s3, &self.stores.s3);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("Invalid configuration:\n  {}", errors.join("\n  ")))
+        }
+    }
+}
+
+fn check_store_ref<T>(
+    errors: &mut Vec<String>,
+    app: &str,
+    kind: &str,
+    name: &Option<String>,
+    stores: &HashMap<String, T>,
+) {
+    if let Some(name) = name {
+        if !stores.contains_key(name) {
+            errors.push(format!(
+                "applications.overrides.{}.{} refers to unknown store '{}'",
+                app, kind, name
+            ));
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use crate::metadata::Verification;
+use anyhow::anyhow;
+use lru::LruCache;
+use oci_distribution::{client, secrets::RegistryAuth, Reference};
+use p256::ecdsa::{signature::Verifier as _, Signature, VerifyingKey};
+use p256::pkcs8::DecodePublicKey;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+const SIMPLESIGNING_MEDIA_TYPE: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// How to treat firmware images without a valid signature.
+#[derive(clap::ArgEnum, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SignaturePolicy {
+    /// Do not serve firmware without a valid signature
+    Require,
+    /// Serve firmware without a valid signature, but report it in the firmware status
+    Warn,
+    /// Do not verify signatures
+    #[default]
+    Off,
+}
+
+/// Verifies cosign signatures of images, stored with the `sha256-<digest>.sig` tag.
+pub struct Verifier {
+    keys: Vec<VerifyingKey>,
+    // Definitive verification result by image digest
+    cache: LruCache<String, Result<(), String>>,
+}
+
+impl Verifier {
+    pub fn new(public_keys: &[PathBuf], cache_size: usize) -> Result<Self, anyhow::Error> {
+        let mut keys = Vec::new();
+        for path in public_keys {
+            let pem = std::fs::read_to_string(path)?;
+            keys.push(
+                VerifyingKey::from_public_key_pem(&pem)
+                    .map_err(|e| anyhow!("Invalid public key {:?}: {}", path, e))?,
+            );
+        }
+        Ok(Self {
+            keys,
+            cache: LruCache::new(cache_size),
+        })
+    }
+
+    /// Verify the signature of an image, returning None if the policy is off.
+    pub async fn verify(
+        &mut self,
+        client: &mut client::Client,
+        auth: &RegistryAuth,
+        imageref: &Reference,
+        digest: &str,
+        policy: SignaturePolicy,
+    ) -> Option<Verification> {
+        let result = match policy {
+            SignaturePolicy::Off => return None,
+            _ => match self.cache.get(digest) {
+                Some(result) => result.clone(),
+                None => match self.lookup(client, auth, imageref, digest).await {
+                    Ok(result) => {
+                        self.cache.put(digest.to_string(), result.clone());
+                        result
+                    }
+                    // Not cached, the signature may not be pushed yet or the registry may be unavailable
+                    Err(e) => Err(e.to_string()),
+                },
+            },
+        };
+        match (result, policy) {
+            (Ok(()), _) => Some(Verification::Verified),
+            (Err(e), SignaturePolicy::Require) => {
+                log::warn!("Rejecting image {}: {}", imageref, e);
+                Some(Verification::Rejected(e))
+            }
+            (Err(e), _) => {
+                log::warn!("Serving image {} despite: {}", imageref, e);
+                Some(Verification::Failed(e))
+            }
+        }
+    }
+
+    /// Look up the signatures of an image, with the verification result if they could be read.
+    async fn lookup(
+        &self,
+        client: &mut client::Client,
+        auth: &RegistryAuth,
+        imageref: &Reference,
+        digest: &str,
+    ) -> Result<Result<(), String>, anyhow::Error> {
+        if self.keys.is_empty() {
+            return Ok(Err(
+                "No public keys configured for signature verification".to_string()
+            ));
+        }
+
+        let sigref = Reference::with_tag(
+            imageref.registry().to_string(),
+            imageref.repository().to_string(),
+            format!("{}.sig", digest.replace(':', "-")),
+        );
+        let (manifest, _) = client
+            .pull_image_manifest(&sigref, auth)
+            .await
+            .map_err(|e| anyhow!("Unable to find signature {}: {}", sigref, e))?;
+
+        for layer in manifest.layers.iter() {
+            if layer.media_type != SIMPLESIGNING_MEDIA_TYPE {
+                continue;
+            }
+            if let Some(signature) = layer
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get(SIGNATURE_ANNOTATION))
+            {
+                let mut payload = Vec::new();
+                client
+                    .pull_blob(&sigref, &layer.digest, &mut payload)
+                    .await?;
+                // Blobs are read regardless of the response status, don't take errors for bad signatures
+                let payload_digest = format!("sha256:{}", hex::encode(Sha256::digest(&payload)));
+                if payload_digest != layer.digest {
+                    return Err(anyhow!(
+                        "Unable to read signature payload {} of {}",
+                        layer.digest,
+                        sigref
+                    ));
+                }
+                match verify_payload(&self.keys, &payload, signature, digest) {
+                    Ok(()) => return Ok(Ok(())),
+                    Err(e) => log::debug!("Signature in {} not valid: {}", sigref, e),
+                }
+            }
+        }
+        Ok(Err(format!("No valid signature found in {}", sigref)))
+    }
+}
+
+#[derive(Deserialize)]
+struct SimpleSigning {
+    critical: Critical,
+}
+
+#[derive(Deserialize)]
+struct Critical {
+    image: Image,
+}
+
+#[derive(Deserialize)]
+struct Image {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+/// Check that a simple signing payload is signed by one of the keys, and refers to the image digest.
+fn verify_payload(
+    keys: &[VerifyingKey],
+    payload: &[u8],
+    signature: &str,
+    digest: &str,
+) -> Result<(), anyhow::Error> {
+    let signature = Signature::from_der(&base64::decode(signature)?)
+        .map_err(|e| anyhow!("Invalid signature: {}", e))?;
+    if !keys.iter().any(|k| k.verify(payload, &signature).is_ok()) {
+        return Err(anyhow!("Signature does not match any public key"));
+    }
+
+    let payload: SimpleSigning = serde_json::from_slice(payload)?;
+    if payload.critical.image.docker_manifest_digest != digest {
+        return Err(anyhow!(
+            "Signature is for image {}",
+            payload.critical.image.docker_manifest_digest
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use p256::ecdsa::{signature::Signer, SigningKey};
+    use serde_json::json;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const DIGEST: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    fn sign(key: &SigningKey, payload: &[u8]) -> String {
+        let signature: Signature = key.sign(payload);
+        base64::encode(signature.to_der())
+    }
+
+    #[test]
+    fn verify_simplesigning() {
+        let key = SigningKey::from_bytes(&[1; 32]).unwrap();
+        let other = SigningKey::from_bytes(&[2; 32]).unwrap();
+        let keys = vec![VerifyingKey::from(&key)];
+        let payload = format!(
+            r#"{{"critical":{{"identity":{{"docker-reference":"quay.io/org/firmware"}},"image":{{"docker-manifest-digest":"{}"}},"type":"cosign container image signature"}},"optional":null}}"#,
+            DIGEST
+        );
+        let payload = payload.as_bytes();
+
+        assert!(verify_payload(&keys, payload, &sign(&key, payload), DIGEST).is_ok());
+        assert!(verify_payload(&keys, payload, &sign(&other, payload), DIGEST).is_err());
+        assert!(verify_payload(&keys, payload, &sign(&key, payload), "sha256:1234").is_err());
+        assert!(verify_payload(&keys, payload, &sign(&key, b"other"), DIGEST).is_err());
+    }
+
+    #[tokio::test]
+    async fn cache_definitive_results() {
+        // Signed, but by a key other than the configured one
+        let key = SigningKey::from_bytes(&[1; 32]).unwrap();
+        let other = SigningKey::from_bytes(&[2; 32]).unwrap();
+        let payload = format!(
+            r#"{{"critical":{{"image":{{"docker-manifest-digest":"{}"}}}}}}"#,
+            DIGEST
+        );
+        let payload_digest = format!("sha256:{}", hex::encode(Sha256::digest(&payload)));
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": payload_digest,
+                "size": payload.len(),
+            },
+            "layers": [{
+                "mediaType": SIMPLESIGNING_MEDIA_TYPE,
+                "digest": payload_digest,
+                "size": payload.len(),
+                "annotations": {SIGNATURE_ANNOTATION: sign(&other, payload.as_bytes())},
+            }],
+        })
+        .to_string();
+
+        // Registry stub without the signature until it is pushed
+        let pushed = Arc::new(AtomicBool::new(false));
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let service = {
+            let (pushed, lookups) = (pushed.clone(), lookups.clone());
+            make_service_fn(move |_| {
+                let (pushed, lookups, manifest, payload) = (
+                    pushed.clone(),
+                    lookups.clone(),
+                    manifest.clone(),
+                    payload.clone(),
+                );
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let path = req.uri().path();
+                        let response = if path.contains("/manifests/") {
+                            lookups.fetch_add(1, Ordering::SeqCst);
+                            if pushed.load(Ordering::SeqCst) {
+                                Response::new(Body::from(manifest.clone()))
+                            } else {
+                                Response::builder()
+                                    .status(404)
+                                    .body(Body::from(r#"{"errors":[]}"#))
+                                    .unwrap()
+                            }
+                        } else if path.contains("/blobs/") {
+                            Response::new(Body::from(payload.clone()))
+                        } else {
+                            Response::new(Body::empty())
+                        };
+                        async move { Ok::<_, Infallible>(response) }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let mut verifier = Verifier {
+            keys: vec![VerifyingKey::from(&key)],
+            cache: LruCache::new(10),
+        };
+        let mut client = client::Client::new(client::ClientConfig {
+            protocol: client::ClientProtocol::Http,
+            ..Default::default()
+        });
+        let imageref: Reference = format!("{}/org/firmware@{}", addr, DIGEST).parse().unwrap();
+
+        // Missing signatures are looked up again, invalid ones are not
+        for (signed, expected) in [(false, 2), (true, 3)] {
+            pushed.store(signed, Ordering::SeqCst);
+            for _ in 0..2 {
+                let verification = verifier
+                    .verify(
+                        &mut client,
+                        &RegistryAuth::Anonymous,
+                        &imageref,
+                        DIGEST,
+                        SignaturePolicy::Require,
+                    )
+                    .await;
+                assert!(matches!(verification, Some(Verification::Rejected(_))));
+            }
+            assert_eq!(expected, lookups.load(Ordering::SeqCst));
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::anyhow;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Container registry credentials, looked up by registry.
+///
+/// Credentials are taken from a docker config file (such as a mounted Kubernetes pull secret) if it
+/// has an entry for the registry, otherwise from the configured user and token. The user and token
+/// are only sent to the registry of the store prefix, other registries are accessed anonymously.
+/// Files are re-read when modified, so rotated credentials are picked up.
+pub struct RegistryCredentials {
+    // Registry of the store prefix, which the user and token belong to
+    registry: Option<String>,
+    user: Option<String>,
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+    docker_config: Option<PathBuf>,
+
+    // Parsed files, along with their modification time
+    token_cache: Option<(SystemTime, String)>,
+    docker_config_cache: Option<(SystemTime, DockerConfig)>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+}
+
+#[derive(Deserialize, Clone)]
+struct DockerAuth {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl RegistryCredentials {
+    pub fn new(
+        prefix: &str,
+        user: Option<String>,
+        token: Option<String>,
+        token_file: Option<PathBuf>,
+        docker_config: Option<PathBuf>,
+    ) -> Self {
+        // Prefixes without a registry host are resolved the same as image references
+        let registry = format!("{}image", prefix)
+            .parse::<Reference>()
+            .ok()
+            .map(|r| normalize(r.registry()).to_string());
+        Self {
+            registry,
+            user,
+            token,
+            token_file,
+            docker_config,
+            token_cache: None,
+            docker_config_cache: None,
+        }
+    }
+
+    /// Credentials to use for a registry.
+    pub fn auth(&mut self, registry: &str) -> Result<RegistryAuth, anyhow::Error> {
+        if let Some(auth) = self.docker_config_auth(registry)? {
+            return Ok(auth);
+        }
+        if self.registry.as_deref() != Some(normalize(registry)) {
+            return Ok(RegistryAuth::Anonymous);
+        }
+
+        let token = match &self.token_file {
+            Some(path) => Some(read_cached(path, &mut self.token_cache, |data| {
+                Ok(data.trim().to_string())
+            })?),
+            None => self.token.clone(),
+        };
+        Ok(token
+            .map(|t| RegistryAuth::Basic(self.user.clone().unwrap_or_default(), t))
+            .unwrap_or(RegistryAuth::Anonymous))
+    }
+
+    fn docker_config_auth(
+        &mut self,
+        registry: &str,
+    ) -> Result<Option<RegistryAuth>, anyhow::Error> {
+        let path = match &self.docker_config {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let config = read_cached(path, &mut self.docker_config_cache, |data| {
+            Ok(serde_json::from_str(data)?)
+        })?;
+
+        let registry = normalize(registry);
+        for (name, auth) in config.auths.iter() {
+            if normalize(name) != registry {
+                continue;
+            }
+            if let (Some(username), Some(password)) = (&auth.username, &auth.password) {
+                return Ok(Some(RegistryAuth::Basic(
+                    username.clone(),
+                    password.clone(),
+                )));
+            }
+            if let Some(auth) = &auth.auth {
+                let decoded = String::from_utf8(base64::decode(auth)?)?;
+                let (username, password) = decoded
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Invalid auth for registry {} in {:?}", name, path))?;
+                return Ok(Some(RegistryAuth::Basic(
+                    username.to_string(),
+                    password.to_string(),
+                )));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Read and parse a file, reusing the previous result if the file is unmodified.
+fn read_cached<T: Clone>(
+    path: &PathBuf,
+    cache: &mut Option<(SystemTime, T)>,
+    parse: impl Fn(&str) -> Result<T, anyhow::Error>,
+) -> Result<T, anyhow::Error> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    if let Some((m, value)) = cache {
+        if *m == modified {
+            return Ok(value.clone());
+        }
+    }
+    log::debug!("Reading credentials from {:?}", path);
+    let value = parse(&std::fs::read_to_string(path)?)?;
+    *cache = Some((modified, value.clone()));
+    Ok(value)
+}
+
+/// Registry host of a docker config entry, which may be a URL.
+fn normalize(registry: &str) -> &str {
+    let registry = registry
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let registry = registry.split('/').next().unwrap_or(registry);
+    match registry {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io",
+        _ => registry,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn docker_config() {
+        let path = std::env::temp_dir().join(format!("ajour-dockerconfig-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{
+                "auths": {
+                    "https://index.docker.io/v1/": { "auth": "dXNlcjpzZWNyZXQ=" },
+                    "quay.io": { "username": "robot", "password": "token" }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let mut credentials = RegistryCredentials::new(
+            "registry.example.com:5000/firmware/",
+            Some("fallback".to_string()),
+            Some("fallback-token".to_string()),
+            None,
+            Some(path.clone()),
+        );
+        let basic = |auth| match auth {
+            RegistryAuth::Basic(user, password) => Some((user, password)),
+            RegistryAuth::Anonymous => None,
+        };
+
+        assert_eq!(
+            Some(("user".to_string(), "secret".to_string())),
+            basic(credentials.auth("docker.io").unwrap())
+        );
+        assert_eq!(
+            Some(("robot".to_string(), "token".to_string())),
+            basic(credentials.auth("quay.io").unwrap())
+        );
+        assert_eq!(
+            Some(("fallback".to_string(), "fallback-token".to_string())),
+            basic(credentials.auth("registry.example.com:5000").unwrap())
+        );
+        // The fallback is only for the registry of the prefix
+        assert_eq!(None, basic(credentials.auth("ghcr.io").unwrap()));
+        assert_eq!(
+            None,
+            basic(credentials.auth("registry.example.com").unwrap())
+        );
+
+        // A prefix without registry host is on docker.io
+        let mut credentials = RegistryCredentials::new(
+            "myorg/",
+            Some("fallback".to_string()),
+            Some("fallback-token".to_string()),
+            None,
+            None,
+        );
+        assert_eq!(
+            Some(("fallback".to_string(), "fallback-token".to_string())),
+            basic(credentials.auth("docker.io").unwrap())
+        );
+        assert_eq!(None, basic(credentials.auth("quay.io").unwrap()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+use ajour_schema::LabelSelector;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::index::DrogueClient;
+
+/// Discovers the applications to manage firmware updates for.
+pub struct Discovery {
+    client: DrogueClient,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    selector: Option<LabelSelector>,
+    interval: Option<Duration>,
+}
+
+impl Discovery {
+    pub fn new(
+        client: DrogueClient,
+        include: Vec<String>,
+        exclude: Vec<String>,
+        selector: Option<LabelSelector>,
+        interval: Option<Duration>,
+    ) -> Self {
+        Self {
+            client,
+            include,
+            exclude,
+            selector,
+            interval,
+        }
+    }
+
+    /// Interval between discovery runs, or None if the application list is static.
+    pub fn interval(&self) -> Option<Duration> {
+        if self.include.is_empty() {
+            self.interval
+        } else {
+            None
+        }
+    }
+
+    /// List the applications that should currently be managed.
+    pub async fn applications(&self) -> Result<HashSet<String>, anyhow::Error> {
+        if !self.include.is_empty() {
+            return Ok(self.include.iter().cloned().collect());
+        }
+
+        let apps = self.client.list_apps(None).await?.unwrap_or_default();
+        Ok(apps
+            .into_iter()
+            .filter(|app| !self.exclude.contains(&app.metadata.name))
+            .filter(|app| {
+                self.selector
+                    .as_ref()
+                    .map(|s| s.matches(&app.metadata.labels))
+                    .unwrap_or(true)
+            })
+            .map(|app| app.metadata.name)
+            .collect())
+    }
+}
@@ -1,9 +1,17 @@
 use crate::metadata::Metadata;
 use crate::updater::FirmwareStore;
+use crate::validate;
+use anyhow::anyhow;
+use lru::LruCache;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileMetadata {
@@ -12,38 +20,268 @@ pub struct FileMetadata {
     pub size: u32,
 }
 
-impl Into<Metadata> for FileMetadata {
-    fn into(self) -> Metadata {
+/// Metadata of the versioned layout, where the version defaults to the version directory.
+#[derive(Deserialize)]
+struct VersionedMetadata {
+    version: Option<String>,
+    checksum: String,
+    size: u32,
+}
+
+impl From<FileMetadata> for Metadata {
+    fn from(metadata: FileMetadata) -> Self {
         Metadata {
-            version: self.version.as_bytes().to_vec(),
-            checksum: self.checksum,
-            size: self.size,
+            version: metadata.version.as_bytes().to_vec(),
+            checksum: metadata.checksum,
+            size: metadata.size,
+            verification: None,
         }
     }
 }
 
+/// Version requesting the highest version of a firmware.
+const LATEST: &str = "latest";
+
+/// Magic number of an MCUboot image header.
+const MCUBOOT_MAGIC: u32 = 0x96f3b83d;
+
+/// Firmware stored either as `<name>.json` and `<name>.bin`, or versioned as
+/// `<name>/<version>/firmware.json` and `<name>/<version>/firmware.bin`.
 pub struct FileClient {
     path: PathBuf,
+    versions: Arc<Mutex<Versions>>,
+    watcher: Option<RecommendedWatcher>,
+
+    // Derive metadata from the firmware when no metadata file exists
+    auto_metadata: bool,
+    // Derived metadata by firmware file, along with its modification time
+    derived: LruCache<PathBuf, (SystemTime, FileMetadata)>,
 }
 
+/// Latest version by firmware name, cleared whenever the directory changes.
+#[derive(Default)]
+struct Versions {
+    generation: u64,
+    latest: HashMap<String, String>,
+}
+
+/// Firmware name and version, where no version or `latest` selects the highest version.
+pub type FileParams = (String, Option<String>);
+
 impl FileClient {
-    pub fn new(path: &PathBuf) -> Self {
-        Self { path: path.clone() }
+    pub fn new(path: &PathBuf, auto_metadata: bool, cache_size: usize) -> Self {
+        let versions = Arc::new(Mutex::new(Versions::default()));
+        let watcher = Self::watch(path, versions.clone())
+            .map_err(|e| {
+                log::warn!(
+                    "Unable to watch {:?}, latest versions are resolved on every request: {:?}",
+                    path,
+                    e
+                );
+            })
+            .ok();
+        Self {
+            path: path.clone(),
+            versions,
+            watcher,
+            auto_metadata,
+            derived: LruCache::new(cache_size),
+        }
+    }
+
+    fn watch(
+        path: &Path,
+        versions: Arc<Mutex<Versions>>,
+    ) -> Result<RecommendedWatcher, notify::Error> {
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event {
+                    log::debug!("Firmware directory changed: {:?}", event.paths);
+                    let mut versions = versions.lock().unwrap();
+                    versions.generation += 1;
+                    versions.latest.clear();
+                }
+            })?;
+        watcher.watch(path, RecursiveMode::Recursive)?;
+        Ok(watcher)
+    }
+
+    /// Resolve the directory and version of a versioned firmware, or None for the flat layout.
+    ///
+    /// Name and version come from the spec, and must not point outside of the store path.
+    fn resolve(
+        &self,
+        name: &str,
+        version: Option<&str>,
+    ) -> Result<Option<(PathBuf, String)>, anyhow::Error> {
+        let dir = self.path.join(validate::file_name(name)?);
+        match version {
+            Some(version) if version != LATEST => {
+                let version = validate::file_name(version)?;
+                Ok(Some((dir.join(version), version.to_string())))
+            }
+            _ if dir.is_dir() => {
+                let version = self.latest(name, &dir)?;
+                Ok(Some((dir.join(&version), version)))
+            }
+            Some(_) => Err(anyhow!("No versions of firmware {} found", name)),
+            None => Ok(None),
+        }
+    }
+
+    /// Find the highest semantic version in a firmware directory.
+    fn latest(&self, name: &str, dir: &Path) -> Result<String, anyhow::Error> {
+        let generation = {
+            let versions = self.versions.lock().unwrap();
+            if self.watcher.is_some() {
+                if let Some(version) = versions.latest.get(name) {
+                    return Ok(version.clone());
+                }
+            }
+            versions.generation
+        };
+
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.path().is_dir() {
+                let version = entry.file_name().to_string_lossy().to_string();
+                match semver::Version::parse(version.trim_start_matches('v')) {
+                    Ok(v) => found.push((v, version)),
+                    Err(_) => log::debug!(
+                        "Ignoring version {:?}, not a semantic version",
+                        entry.path()
+                    ),
+                }
+            }
+        }
+        let (_, latest) = found
+            .into_iter()
+            .max()
+            .ok_or_else(|| anyhow!("No versions of firmware {} found", name))?;
+
+        // Only cache if the directory did not change while scanning
+        let mut versions = self.versions.lock().unwrap();
+        if versions.generation == generation {
+            versions.latest.insert(name.to_string(), latest.clone());
+        }
+        Ok(latest)
     }
+
+    /// Read the metadata file, or derive the metadata from the firmware file if allowed.
+    fn metadata(
+        &mut self,
+        metadata: &Path,
+        firmware: &Path,
+        version: Option<String>,
+    ) -> Result<FileMetadata, anyhow::Error> {
+        log::debug!("Looking for metadata from {:?}", metadata);
+        match File::open(metadata) {
+            Ok(f) => match version {
+                Some(version) => {
+                    let metadata: VersionedMetadata = serde_json::from_reader(f)?;
+                    Ok(FileMetadata {
+                        version: metadata.version.unwrap_or(version),
+                        checksum: metadata.checksum,
+                        size: metadata.size,
+                    })
+                }
+                None => Ok(serde_json::from_reader(f)?),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound && self.auto_metadata => {
+                self.derive_metadata(firmware, version)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Compute checksum and size of a firmware file, cached until the file is modified.
+    ///
+    /// The version is read from an MCUboot image header if present, otherwise the version
+    /// directory is used. Firmware in the flat layout without a header needs a version in its
+    /// name, such as `app-1.2.0.bin`.
+    fn derive_metadata(
+        &mut self,
+        firmware: &Path,
+        version: Option<String>,
+    ) -> Result<FileMetadata, anyhow::Error> {
+        let modified = std::fs::metadata(firmware)?.modified()?;
+        if let Some((m, metadata)) = self.derived.get(&firmware.to_path_buf()) {
+            if *m == modified {
+                return Ok(metadata.clone());
+            }
+        }
+
+        log::debug!("Deriving metadata from {:?}", firmware);
+        let data = std::fs::read(firmware)?;
+        let checksum = hex::encode(Sha256::digest(&data));
+        let version = mcuboot_version(&data)
+            .or(version)
+            .or_else(|| name_version(firmware))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unable to derive the version of {:?}, it has no MCUboot header or version in its name",
+                    firmware
+                )
+            })?;
+        let metadata = FileMetadata {
+            version,
+            checksum,
+            size: data.len() as u32,
+        };
+        self.derived
+            .put(firmware.to_path_buf(), (modified, metadata.clone()));
+        Ok(metadata)
+    }
+}
+
+/// Version of an MCUboot image, formatted as `major.minor.revision[+build]`.
+fn mcuboot_version(data: &[u8]) -> Option<String> {
+    let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    if data.len() < 32 || u32_at(0) != MCUBOOT_MAGIC {
+        return None;
+    }
+    let (major, minor, revision, build) = (data[20], data[21], u16_at(22), u32_at(24));
+    if build == 0 {
+        Some(format!("{}.{}.{}", major, minor, revision))
+    } else {
+        Some(format!("{}.{}.{}+{}", major, minor, revision, build))
+    }
+}
+
+/// Semantic version at the end of a file name, such as `1.2.0` of `app-1.2.0.bin`.
+fn name_version(firmware: &Path) -> Option<String> {
+    let name = firmware.file_stem()?.to_str()?;
+    name.match_indices('-')
+        .map(|(i, _)| &name[i + 1..])
+        .find(|v| semver::Version::parse(v.trim_start_matches('v')).is_ok())
+        .map(|v| v.to_string())
 }
 
 #[async_trait::async_trait]
 impl FirmwareStore for FileClient {
-    type Params = String;
+    type Params = FileParams;
 
     async fn fetch_metadata(
         &mut self,
         params: &Self::Params,
     ) -> Result<(Self::Context, Option<Metadata>), anyhow::Error> {
-        let f = self.path.join(format!("{}.json", params));
-        log::debug!("Looking for metadata from {:?}", f);
-        let f: FileMetadata = serde_json::from_reader(File::open(f)?)?;
-        Ok(((), Some(f.try_into()?)))
+        let (name, version) = params;
+        let (metadata, firmware, version) = match self.resolve(name, version.as_deref())? {
+            Some((dir, version)) => (
+                dir.join("firmware.json"),
+                dir.join("firmware.bin"),
+                Some(version),
+            ),
+            None => (
+                self.path.join(format!("{}.json", name)),
+                self.path.join(format!("{}.bin", name)),
+                None,
+            ),
+        };
+        let metadata = self.metadata(&metadata, &firmware, version)?;
+        Ok((firmware, Some(metadata.into())))
     }
 
     async fn update_progress(
@@ -52,6 +290,7 @@ impl FirmwareStore for FileClient {
         _: &Self::Context,
         _: u32,
         _: u32,
+        _: &str,
     ) -> Result<(), anyhow::Error> {
         Ok(())
     }
@@ -65,14 +304,23 @@ impl FirmwareStore for FileClient {
         Ok(())
     }
 
-    type Context = ();
-    async fn fetch_firmware(
+    async fn mark_failed(
         &mut self,
-        params: &Self::Params,
+        _: &Self::Params,
         _: &Self::Context,
+        _: &str,
+    ) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    // Firmware file matching the metadata
+    type Context = PathBuf;
+    async fn fetch_firmware(
+        &mut self,
+        _: &Self::Params,
+        f: &Self::Context,
         _: &Metadata,
     ) -> Result<Vec<u8>, anyhow::Error> {
-        let f = self.path.join(format!("{}.bin", params));
         log::debug!("Reading firmware from from {:?}", f);
         let mut f = File::open(f)?;
         let mut data = Vec::new();
@@ -80,3 +328,135 @@ impl FirmwareStore for FileClient {
         Ok(data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn add_version(dir: &Path, version: &str) {
+        let dir = dir.join("app").join(version);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("firmware.json"), r#"{"checksum":"abcd","size":4}"#).unwrap();
+        std::fs::write(dir.join("firmware.bin"), version.as_bytes()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn versioned_layout() {
+        let dir = std::env::temp_dir().join(format!("ajour-file-test-{}", std::process::id()));
+        add_version(&dir, "0.9.0");
+        add_version(&dir, "0.10.0");
+        add_version(&dir, "snapshot");
+
+        let mut client = FileClient::new(&dir, false, 10);
+        let version = |v: Option<&str>| ("app".to_string(), v.map(|v| v.to_string()));
+
+        let (f, metadata) = client.fetch_metadata(&version(None)).await.unwrap();
+        assert_eq!(b"0.10.0".to_vec(), metadata.unwrap().version);
+        assert_eq!(dir.join("app").join("0.10.0").join("firmware.bin"), f);
+
+        let (f, metadata) = client
+            .fetch_metadata(&version(Some("0.9.0")))
+            .await
+            .unwrap();
+        let metadata = metadata.unwrap();
+        assert_eq!(b"0.9.0".to_vec(), metadata.version);
+        let firmware = client
+            .fetch_firmware(&version(Some("0.9.0")), &f, &metadata)
+            .await
+            .unwrap();
+        assert_eq!(b"0.9.0".to_vec(), firmware);
+
+        // New versions are picked up through the watcher
+        add_version(&dir, "1.0.0");
+        let mut latest = Vec::new();
+        for _ in 0..50 {
+            let (_, metadata) = client
+                .fetch_metadata(&version(Some("latest")))
+                .await
+                .unwrap();
+            latest = metadata.unwrap().version;
+            if latest == b"1.0.0" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(b"1.0.0".to_vec(), latest);
+
+        // Only the versioned layout defaults to the version directory
+        std::fs::write(
+            dir.join("app").join("0.9.0").join("firmware.json"),
+            r#"{"version":"0.9.0-rc1","checksum":"abcd","size":4}"#,
+        )
+        .unwrap();
+        let (_, metadata) = client
+            .fetch_metadata(&version(Some("0.9.0")))
+            .await
+            .unwrap();
+        assert_eq!(b"0.9.0-rc1".to_vec(), metadata.unwrap().version);
+        std::fs::write(dir.join("flat.json"), r#"{"checksum":"abcd","size":4}"#).unwrap();
+        let flat = ("flat".to_string(), None);
+        assert!(client.fetch_metadata(&flat).await.is_err());
+
+        // Paths outside of the store are refused
+        assert!(client
+            .fetch_metadata(&version(Some("../../0.9.0")))
+            .await
+            .is_err());
+        assert!(client.fetch_metadata(&version(Some("/etc"))).await.is_err());
+        let outside = ("../app".to_string(), Some("0.9.0".to_string()));
+        assert!(client.fetch_metadata(&outside).await.is_err());
+        let outside = ("..".to_string(), None);
+        assert!(client.fetch_metadata(&outside).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn derived_metadata() {
+        let dir = std::env::temp_dir().join(format!("ajour-derive-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("app").join("2.0.0")).unwrap();
+
+        // MCUboot header with version 1.2.3+4
+        let mut image = vec![0; 64];
+        image[0..4].copy_from_slice(&MCUBOOT_MAGIC.to_le_bytes());
+        image[20..28].copy_from_slice(&[1, 2, 3, 0, 4, 0, 0, 0]);
+        std::fs::write(dir.join("mcuboot.bin"), &image).unwrap();
+        std::fs::write(dir.join("plain.bin"), b"firmware").unwrap();
+        std::fs::write(dir.join("my-app-1.2.0-rc.1.bin"), b"firmware").unwrap();
+        std::fs::write(
+            dir.join("app").join("2.0.0").join("firmware.bin"),
+            b"firmware",
+        )
+        .unwrap();
+
+        let params = |name: &str| (name.to_string(), None);
+        let mut client = FileClient::new(&dir, false, 10);
+        assert!(client.fetch_metadata(&params("plain")).await.is_err());
+
+        let mut client = FileClient::new(&dir, true, 10);
+        let (_, metadata) = client.fetch_metadata(&params("mcuboot")).await.unwrap();
+        let metadata = metadata.unwrap();
+        assert_eq!(b"1.2.3+4".to_vec(), metadata.version);
+        assert_eq!(64, metadata.size);
+
+        let (_, metadata) = client.fetch_metadata(&params("app")).await.unwrap();
+        assert_eq!(b"2.0.0".to_vec(), metadata.unwrap().version);
+
+        let (_, metadata) = client
+            .fetch_metadata(&params("my-app-1.2.0-rc.1"))
+            .await
+            .unwrap();
+        let metadata = metadata.unwrap();
+        assert_eq!(
+            "c3bf47ea1f4a4a605470313cacb3a44f4a461f68c6faeab07e737610cb5ac835",
+            metadata.checksum
+        );
+        assert_eq!(b"1.2.0-rc.1".to_vec(), metadata.version);
+
+        // No version in the header or name
+        assert!(client.fetch_metadata(&params("plain")).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,239 +1,700 @@
 use crate::metadata::Metadata;
 use crate::updater::FirmwareStore;
+use crate::validate;
+use anyhow::anyhow;
+use drogue_client::registry::v1::Device;
+use lru::LruCache;
+use serde::Deserialize;
 use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::time::Duration;
 
+/// Client of the Hawkbit DDI API, for the tenants of a Hawkbit instance or of other instances
+/// given in the firmware spec. All tenants share one HTTP client.
 pub struct HawkbitClient {
     client: reqwest::Client,
+    // Default instance and tenant
     url: String,
     tenant: String,
-    token: String,
+    token: Option<String>,
+    // Directory of secrets, each a directory with a `gateway-token` or `target-token` file
+    secrets: Option<PathBuf>,
+    // Other instances firmware specs may point to
+    instances: Vec<String>,
+
+    // Attributes last registered, by controller URL
+    registered: LruCache<String, HashMap<String, String>>,
+}
+
+/// Parameters of a firmware lookup in Hawkbit.
+pub struct HawkbitParams {
+    pub controller: String,
+    /// Hawkbit instance, if not the one configured for the store
+    pub url: Option<String>,
+    /// Tenant, if not the one configured for the store
+    pub tenant: Option<String>,
+    /// Secret with the gateway or target token
+    pub secret: Option<String>,
+    /// Software module type of the chunk to use, the first chunk with artifacts if not set
+    pub part: Option<String>,
+    /// File name of the artifact to use, the first artifact of the chunk if not set
+    pub artifact: Option<String>,
 }
 
 pub enum PollResult {
-    Wait(Duration),
+    Wait(Option<Duration>),
     Deployment(Deployment),
+    /// A deployment was cancelled, and the cancellation acknowledged
+    Cancelled,
 }
 
 pub struct Deployment {
     id: String,
     path: String,
+    download: HandlingType,
+    update: HandlingType,
+    maintenance_window: Option<MaintenanceWindow>,
+    // Polling interval while the deployment is held
+    sleep: Option<Duration>,
+}
+
+impl Deployment {
+    fn hold_download(&self) -> bool {
+        self.download == HandlingType::Skip
+    }
+
+    // Hawkbit skips the update for download only deployments, and outside the maintenance window
+    fn hold_swap(&self) -> bool {
+        self.update == HandlingType::Skip
+    }
+
+    fn download_only(&self) -> bool {
+        self.hold_swap() && self.maintenance_window != Some(MaintenanceWindow::Unavailable)
+    }
+}
+
+// Subset of the Hawkbit DDI API responses used
+
+#[derive(Deserialize, Debug)]
+struct ControllerBase {
+    config: Option<ControllerConfig>,
+    #[serde(rename = "_links", default)]
+    links: ControllerLinks,
+}
+
+#[derive(Deserialize, Debug)]
+struct ControllerConfig {
+    polling: Polling,
+}
+
+#[derive(Deserialize, Debug)]
+struct Polling {
+    sleep: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ControllerLinks {
+    #[serde(rename = "deploymentBase")]
+    deployment_base: Option<Link>,
+    #[serde(rename = "cancelAction")]
+    cancel_action: Option<Link>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Link {
+    href: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DeploymentBase {
+    id: String,
+    deployment: DeploymentInfo,
+}
+
+#[derive(Deserialize, Debug)]
+struct DeploymentInfo {
+    #[serde(default)]
+    download: HandlingType,
+    #[serde(default)]
+    update: HandlingType,
+    #[serde(rename = "maintenanceWindow")]
+    maintenance_window: Option<MaintenanceWindow>,
+    #[serde(default)]
+    chunks: Vec<Chunk>,
+}
+
+/// Whether a step of a deployment should be skipped, attempted or forced.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum HandlingType {
+    Skip,
+    Attempt,
+    #[default]
+    Forced,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MaintenanceWindow {
+    Available,
+    Unavailable,
+}
+
+#[derive(Deserialize, Debug)]
+struct Chunk {
+    part: String,
+    version: String,
+    #[serde(default)]
+    artifacts: Vec<Artifact>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Artifact {
+    filename: String,
+    hashes: Hashes,
+    size: u32,
+    #[serde(rename = "_links")]
+    links: ArtifactLinks,
+}
+
+#[derive(Deserialize, Debug)]
+struct Hashes {
+    sha256: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtifactLinks {
+    #[serde(rename = "download-http")]
+    download_http: Option<Link>,
+    download: Option<Link>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CancelAction {
+    id: String,
+}
+
+/// Controller resource of a target, and the authorization to access it.
+struct Controller {
+    name: String,
+    url: String,
+    authorization: String,
 }
 
 impl HawkbitClient {
-    pub fn new(url: &str, tenant: &str, token: &str) -> Self {
+    pub fn new(
+        url: &str,
+        tenant: &str,
+        token: Option<String>,
+        secrets: Option<PathBuf>,
+        instances: Vec<String>,
+        devices_max: usize,
+    ) -> Self {
         Self {
             client: reqwest::Client::new(),
             url: url.to_string(),
             tenant: tenant.to_string(),
-            token: token.to_string(),
+            token,
+            secrets,
+            instances,
+            registered: LruCache::new(devices_max),
         }
     }
 
-    pub async fn register(&self, controller: &str) -> std::io::Result<()> {
-        let url = format!(
-            "{}/{}/controller/v1/{}",
-            &self.url, &self.tenant, controller
-        );
+    /// Resolve the controller resource and credentials of a target.
+    ///
+    /// Instance, tenant and secret come from the spec, tokens are only sent to the configured
+    /// instances.
+    fn controller(&self, params: &HawkbitParams) -> Result<Controller, anyhow::Error> {
+        let url = params.url.as_deref().unwrap_or(&self.url);
+        let same = |a: &str, b: &str| a.trim_end_matches('/') == b.trim_end_matches('/');
+        if !same(url, &self.url) && !self.instances.iter().any(|i| same(url, i)) {
+            return Err(anyhow!(
+                "Hawkbit instance {} is not configured for the store",
+                url
+            ));
+        }
+        let tenant = validate::path_segment(params.tenant.as_deref().unwrap_or(&self.tenant))?;
+
+        let mut authorization = None;
+        if let Some(secret) = &params.secret {
+            // Prefer the token of the target over the token of the tenant
+            if let Some(token) = self.read_token(secret, "target-token")? {
+                authorization = Some(format!("TargetToken {}", token));
+            } else if let Some(token) = self.read_token(secret, "gateway-token")? {
+                authorization = Some(format!("GatewayToken {}", token));
+            }
+        }
+        // The gateway token of the store is only valid for its own tenant
+        if url == self.url && tenant == self.tenant {
+            authorization = authorization.or_else(|| {
+                self.token
+                    .as_ref()
+                    .map(|token| format!("GatewayToken {}", token))
+            });
+        }
+        let authorization = authorization.ok_or_else(|| {
+            anyhow!(
+                "No token for controller {} of Hawkbit tenant {} at {}",
+                params.controller,
+                tenant,
+                url
+            )
+        })?;
+
+        Ok(Controller {
+            name: params.controller.clone(),
+            url: format!(
+                "{}/{}/controller/v1/{}",
+                url.trim_end_matches('/'),
+                tenant,
+                params.controller
+            ),
+            authorization,
+        })
+    }
+
+    /// Read a token from a secret, if the secret has it.
+    fn read_token(&self, secret: &str, key: &str) -> Result<Option<String>, anyhow::Error> {
+        let secret = validate::file_name(secret)?;
+        let path = self
+            .secrets
+            .as_ref()
+            .ok_or_else(|| {
+                anyhow!(
+                    "Firmware requires secret {}, but no secrets path configured",
+                    secret
+                )
+            })?
+            .join(secret)
+            .join(key);
+        if path.is_file() {
+            Ok(Some(std::fs::read_to_string(path)?.trim().to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Set the attributes of a controller, unless they are unchanged since the last registration.
+    pub async fn register(
+        &mut self,
+        params: &HawkbitParams,
+        attributes: HashMap<String, String>,
+    ) -> Result<(), anyhow::Error> {
+        let controller = self.controller(params)?;
+        if self.registered.get(&controller.url) == Some(&attributes) {
+            return Ok(());
+        }
 
-        // TODO: Get attributes from somewhere
-        let attributes = json! {{
+        let data = json! {{
           "mode": "merge",
-          "data": {
-            "VIN": "JH4TB2H26CC000001",
-            "hwRevision": "1"
-          },
-          "status": {
-            "result": {
-              "finished": "success"
-            },
-            "execution": "closed",
-            "details": []
-          }
+          "data": attributes,
         }};
 
         let res = self
             .client
-            .put(&url)
-            .header("Authorization", &format!("GatewayToken {}", &self.token))
+            .put(format!("{}/configData", controller.url))
+            .header("Authorization", &controller.authorization)
             .header("Accept", "application/hal+json")
-            .json(&attributes)
+            .json(&data)
             .send()
-            .await;
+            .await
+            .and_then(|r| r.error_for_status());
         match res {
             Ok(_) => {
-                log::debug!("Successfully set attributes");
+                log::debug!(
+                    "Successfully set attributes of controller {}",
+                    controller.name
+                );
+                self.registered.put(controller.url, attributes);
             }
             Err(e) => {
+                // Retried with the next status from the device
                 log::info!("Error setting attributes: {:?}", e);
             }
         }
         Ok(())
     }
 
-    async fn fetch_firmware(&self, d: &Deployment) -> Result<Vec<u8>, anyhow::Error> {
+    async fn fetch_firmware(
+        &self,
+        controller: &Controller,
+        d: &Deployment,
+    ) -> Result<Vec<u8>, anyhow::Error> {
         let res = self
             .client
             .get(d.path.to_string())
-            .header("Authorization", &format!("GatewayToken {}", &self.token))
+            .header("Authorization", &controller.authorization)
             .header("Accept", "application/hal+json")
             .send()
             .await?
+            .error_for_status()?
             .bytes()
             .await?;
         Ok(res.as_ref().into())
     }
 
+    async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        controller: &Controller,
+        url: &str,
+    ) -> Result<T, anyhow::Error> {
+        Ok(self
+            .client
+            .get(url)
+            .header("Authorization", &controller.authorization)
+            .header("Accept", "application/hal+json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Post feedback for an action, such as `deploymentBase` or `cancelAction`.
     async fn provide_feedback(
         &self,
-        controller: &str,
-        deployment: &Deployment,
+        controller: &Controller,
+        action: &str,
+        id: &str,
         status: serde_json::Value,
     ) -> Result<(), anyhow::Error> {
-        let client = reqwest::Client::new();
-        let url = format!(
-            "{}/{}/controller/v1/{}/deploymentBase/{}/feedback",
-            &self.url, &self.tenant, controller, deployment.id
-        );
+        let url = format!("{}/{}/{}/feedback", controller.url, action, id);
 
         let feedback = json! {
             {
-                "id": deployment.id,
+                "id": id,
                 "status": status,
             }
         };
 
-        client
+        self.client
             .post(&url)
-            .header("Authorization", &format!("GatewayToken {}", &self.token))
+            .header("Authorization", &controller.authorization)
             .header("Accept", "application/hal+json")
             .json(&feedback)
             .send()
-            .await?;
+            .await?
+            .error_for_status()?;
 
         Ok(())
     }
 
-    async fn read_metadata(&self, path: &str) -> Result<(Metadata, Deployment), anyhow::Error> {
-        let client = reqwest::Client::new();
-        let res: serde_json::Value = client
-            .get(path)
-            .header("Authorization", &format!("GatewayToken {}", &self.token))
-            .header("Accept", "application/hal+json")
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        let id = res["id"].as_str().unwrap().to_string();
-        let chunks = &res["deployment"]["chunks"];
-        let chunk = &chunks[0];
-        let version = chunk["version"].as_str().unwrap().to_string();
-        let artifact = &chunk["artifacts"][0];
-        let size: usize = artifact["size"].as_i64().unwrap() as usize;
-        let path = artifact["_links"]["download-http"]["href"]
-            .as_str()
-            .unwrap();
-        let metadata = Metadata {
-            checksum: String::new(),
-            version: version.into(),
-            size: size as u32,
-        };
+    async fn read_metadata(
+        &self,
+        controller: &Controller,
+        params: &HawkbitParams,
+        path: &str,
+    ) -> Result<(Metadata, Deployment), anyhow::Error> {
+        let base: DeploymentBase = self.get(controller, path).await?;
+        read_deployment(params, base)
+    }
 
-        let deployment = Deployment {
-            id,
-            path: path.to_string(),
-        };
-        Ok((metadata, deployment))
+    async fn cancel(&self, controller: &Controller, path: &str) -> Result<(), anyhow::Error> {
+        let cancel: CancelAction = self.get(controller, path).await?;
+        log::info!(
+            "Acknowledging cancellation {} of controller {}",
+            cancel.id,
+            controller.name
+        );
+        let status = json!({
+            "result": {
+                "finished": "success",
+            },
+            "execution": "closed",
+            "details": ["Update cancelled"],
+        });
+        self.provide_feedback(controller, "cancelAction", &cancel.id, status)
+            .await
     }
 
     pub async fn fetch_metadata(
         &self,
-        controller: &str,
+        params: &HawkbitParams,
     ) -> Result<(PollResult, Option<Metadata>), anyhow::Error> {
-        let url = format!(
-            "{}/{}/controller/v1/{}",
-            &self.url, &self.tenant, controller
-        );
-        let res = self
-            .client
-            .get(&url)
-            .header("Authorization", &format!("GatewayToken {}", &self.token))
-            .header("Accept", "application/hal+json")
-            .send()
-            .await?;
+        let controller = self.controller(params)?;
+        let base: ControllerBase = self.get(&controller, &controller.url).await?;
+        let sleep = base
+            .config
+            .map(|c| parse_sleep(&c.polling.sleep))
+            .transpose()?;
 
-        let j: serde_json::Value = res.json().await.unwrap();
+        if let Some(cancel) = base.links.cancel_action {
+            self.cancel(&controller, &cancel.href).await?;
+            return Ok((PollResult::Cancelled, None));
+        }
 
         // If we have a deployment base, return download link
-        if let Some(links) = j.get("_links") {
-            if let Some(base) = links.get("deploymentBase") {
-                if let Some(href) = base.get("href") {
-                    if let Some(path) = href.as_str() {
-                        let (m, p) = self.read_metadata(path).await?;
-                        return Ok((PollResult::Deployment(p), Some(m)));
-                    }
-                }
-            }
+        if let Some(deployment) = base.links.deployment_base {
+            let (m, mut p) = self
+                .read_metadata(&controller, params, &deployment.href)
+                .await?;
+            p.sleep = sleep;
+            return Ok((PollResult::Deployment(p), Some(m)));
         }
 
-        let poll = j["config"]["polling"]["sleep"].as_str().unwrap();
-        let mut s = poll.splitn(3, ":");
-        let mut dur = chrono::Duration::zero();
-        if let Some(d) = s.next() {
-            dur = dur + chrono::Duration::days(d.parse::<i64>().unwrap());
-        }
+        Ok((PollResult::Wait(sleep), None))
+    }
+}
 
-        if let Some(h) = s.next() {
-            dur = dur + chrono::Duration::hours(h.parse::<i64>().unwrap());
-        }
+/// Pick the artifact of a deployment to use, and its metadata.
+fn read_deployment(
+    params: &HawkbitParams,
+    base: DeploymentBase,
+) -> Result<(Metadata, Deployment), anyhow::Error> {
+    let (chunk, artifact) = base
+        .deployment
+        .chunks
+        .iter()
+        .filter(|c| params.part.as_ref().map(|p| p == &c.part).unwrap_or(true))
+        .flat_map(|c| c.artifacts.iter().map(move |a| (c, a)))
+        .find(|(_, a)| {
+            params
+                .artifact
+                .as_ref()
+                .map(|n| n == &a.filename)
+                .unwrap_or(true)
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "No matching artifact in deployment {} of controller {}",
+                base.id,
+                params.controller
+            )
+        })?;
+
+    let checksum = artifact.hashes.sha256.clone().ok_or_else(|| {
+        anyhow!(
+            "Artifact {} of deployment {} has no SHA-256 hash",
+            artifact.filename,
+            base.id
+        )
+    })?;
+    let path = artifact
+        .links
+        .download_http
+        .as_ref()
+        .or(artifact.links.download.as_ref())
+        .ok_or_else(|| {
+            anyhow!(
+                "Artifact {} of deployment {} has no download link",
+                artifact.filename,
+                base.id
+            )
+        })?;
+
+    let metadata = Metadata {
+        checksum,
+        version: chunk.version.clone().into(),
+        size: artifact.size,
+        verification: None,
+    };
+    let deployment = Deployment {
+        id: base.id,
+        path: path.href.clone(),
+        download: base.deployment.download,
+        update: base.deployment.update,
+        maintenance_window: base.deployment.maintenance_window,
+        sleep: None,
+    };
+    Ok((metadata, deployment))
+}
+
+/// Parse a polling interval in the `HH:MM:SS` format.
+fn parse_sleep(sleep: &str) -> Result<Duration, anyhow::Error> {
+    let mut seconds = 0;
+    let mut parts = 0;
+    for part in sleep.split(':') {
+        seconds = seconds * 60
+            + part
+                .parse::<u64>()
+                .map_err(|e| anyhow!("Invalid polling interval '{}': {}", sleep, e))?;
+        parts += 1;
+    }
+    if parts != 3 {
+        return Err(anyhow!("Invalid polling interval '{}'", sleep));
+    }
+    Ok(Duration::from_secs(seconds))
+}
 
-        if let Some(s) = s.next() {
-            dur = dur + chrono::Duration::seconds(s.parse::<i64>().unwrap());
+/// Build the controller attributes of a device from an attribute mapping of the firmware spec.
+///
+/// Without a mapping, the firmware version reported by the device is registered as `version`.
+pub fn controller_attributes(
+    mapping: &HashMap<String, String>,
+    device: Option<&Device>,
+    version: &[u8],
+) -> HashMap<String, String> {
+    let version = String::from_utf8_lossy(version).to_string();
+    if mapping.is_empty() {
+        return HashMap::from([("version".to_string(), version)]);
+    }
+
+    let mut attributes = HashMap::new();
+    for (name, source) in mapping.iter() {
+        let value = match source.split_once(':') {
+            None if source == "version" => Some(version.clone()),
+            Some(("label", label)) => device.and_then(|d| d.metadata.labels.get(label).cloned()),
+            Some(("annotation", annotation)) => {
+                device.and_then(|d| d.metadata.annotations.get(annotation).cloned())
+            }
+            _ => {
+                log::warn!(
+                    "Unknown source '{}' of controller attribute {}",
+                    source,
+                    name
+                );
+                None
+            }
+        };
+        if let Some(value) = value {
+            attributes.insert(name.clone(), value);
         }
-        let s = dur.to_std().unwrap();
-        Ok((PollResult::Wait(s), None))
     }
+    attributes
+}
+
+/// Expand the placeholders of a controller ID template: `{application}`, `{device}`,
+/// `{label:<name>}` and `{annotation:<name>}`.
+///
+/// The controller ID is a segment of the DDI resource path, so only unreserved URL characters are
+/// accepted.
+pub fn controller_id(
+    template: &str,
+    application: &str,
+    name: &str,
+    device: Option<&Device>,
+) -> Result<String, anyhow::Error> {
+    let mut id = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        id.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated placeholder in controller '{}'", template))?
+            + start;
+        let placeholder = &rest[start + 1..end];
+        let value = match placeholder.split_once(':') {
+            None if placeholder == "application" => Some(application.to_string()),
+            None if placeholder == "device" => Some(name.to_string()),
+            Some(("label", label)) => device.and_then(|d| d.metadata.labels.get(label).cloned()),
+            Some(("annotation", annotation)) => {
+                device.and_then(|d| d.metadata.annotations.get(annotation).cloned())
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Unknown placeholder '{}' in controller '{}'",
+                    placeholder,
+                    template
+                ))
+            }
+        };
+        let value = value.ok_or_else(|| {
+            anyhow!(
+                "Device {}/{} has no value for '{}' of controller '{}'",
+                application,
+                name,
+                placeholder,
+                template
+            )
+        })?;
+        id.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    id.push_str(rest);
+    validate::path_segment(&id).map_err(|e| {
+        anyhow!(
+            "Invalid controller '{}' of device {}/{}: {}",
+            template,
+            application,
+            name,
+            e
+        )
+    })?;
+    Ok(id)
 }
 
 #[async_trait::async_trait]
 impl FirmwareStore for HawkbitClient {
-    type Params = String;
+    type Params = HawkbitParams;
     async fn fetch_metadata(
         &mut self,
         params: &Self::Params,
     ) -> Result<(Self::Context, Option<Metadata>), anyhow::Error> {
-        HawkbitClient::fetch_metadata(self, &params).await
+        HawkbitClient::fetch_metadata(self, params).await
     }
 
     fn get_backoff(&self, context: &Self::Context) -> Option<u32> {
-        if let PollResult::Wait(w) = context {
-            Some(w.as_secs() as u32)
-        } else {
-            None
+        match context {
+            PollResult::Wait(Some(w)) => Some(w.as_secs() as u32),
+            PollResult::Deployment(d) => d.sleep.map(|w| w.as_secs() as u32),
+            _ => None,
         }
     }
 
+    fn hold_download(&self, context: &Self::Context) -> bool {
+        matches!(context, PollResult::Deployment(d) if d.hold_download())
+    }
+
+    fn hold_swap(&self, context: &Self::Context) -> bool {
+        matches!(context, PollResult::Deployment(d) if d.hold_swap())
+    }
+
+    fn is_cancelled(&self, context: &Self::Context) -> bool {
+        matches!(context, PollResult::Cancelled)
+    }
+
     async fn update_progress(
         &mut self,
         params: &Self::Params,
         context: &Self::Context,
         offset: u32,
         size: u32,
+        details: &str,
     ) -> Result<(), anyhow::Error> {
         if let PollResult::Deployment(d) = context {
-            let status = json!({
-                "result": {
-                    "progress": {
-                        "of": size,
-                        "cnt": offset,
+            let status = if offset >= size && d.download_only() {
+                // Completes a download only deployment
+                json!({
+                    "result": {
+                        "finished": "success",
                     },
-                    "finished": "none",
-                },
-                "execution": "proceeding",
-                "details": ["Updating..."],
-            });
-            self.provide_feedback(params, d, status).await?;
+                    "execution": "downloaded",
+                    "details": ["Firmware downloaded"],
+                })
+            } else if offset >= size && d.hold_swap() {
+                json!({
+                    "result": {
+                        "finished": "none",
+                    },
+                    "execution": "downloaded",
+                    "details": ["Firmware downloaded, waiting for maintenance window"],
+                })
+            } else if d.hold_download() {
+                json!({
+                    "result": {
+                        "finished": "none",
+                    },
+                    "execution": "scheduled",
+                    "details": ["Waiting for download"],
+                })
+            } else {
+                json!({
+                    "result": {
+                        "progress": {
+                            "of": size,
+                            "cnt": offset,
+                        },
+                        "finished": "none",
+                    },
+                    "execution": "proceeding",
+                    "details": [details],
+                })
+            };
+            let controller = self.controller(params)?;
+            self.provide_feedback(&controller, "deploymentBase", &d.id, status)
+                .await?;
         }
         Ok(())
     }
@@ -243,16 +704,49 @@ impl FirmwareStore for HawkbitClient {
         params: &Self::Params,
         context: &Self::Context,
         success: bool,
+    ) -> Result<(), anyhow::Error> {
+        if let PollResult::Deployment(d) = context {
+            let status = if success {
+                json!({
+                    "result": {
+                        "finished": "success",
+                    },
+                    "execution": "closed",
+                    "details": ["Update was successfully installed."],
+                })
+            } else {
+                json!({
+                    "result": {
+                        "finished": "failure",
+                    },
+                    "execution": "closed",
+                    "details": ["Update was not installed."],
+                })
+            };
+            let controller = self.controller(params)?;
+            self.provide_feedback(&controller, "deploymentBase", &d.id, status)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &mut self,
+        params: &Self::Params,
+        context: &Self::Context,
+        reason: &str,
     ) -> Result<(), anyhow::Error> {
         if let PollResult::Deployment(d) = context {
             let status = json!({
                 "result": {
-                    "finished": if success { "success" } else { "failed" },
+                    "finished": "failure",
                 },
                 "execution": "closed",
-                "details": ["Update was successfully installed."],
+                "details": [reason],
             });
-            self.provide_feedback(params, d, status).await?;
+            let controller = self.controller(params)?;
+            self.provide_feedback(&controller, "deploymentBase", &d.id, status)
+                .await?;
         }
         Ok(())
     }
@@ -260,14 +754,508 @@ impl FirmwareStore for HawkbitClient {
     type Context = PollResult;
     async fn fetch_firmware(
         &mut self,
-        _: &Self::Params,
+        params: &Self::Params,
         context: &Self::Context,
         _: &Metadata,
     ) -> Result<Vec<u8>, anyhow::Error> {
         if let PollResult::Deployment(d) = context {
-            HawkbitClient::fetch_firmware(self, d).await
+            let controller = self.controller(params)?;
+            HawkbitClient::fetch_firmware(self, &controller, d).await
         } else {
-            Err(anyhow::anyhow!("Unexpected PollResult"))
+            Err(anyhow!("Unexpected PollResult"))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTROLLER_BASE: &str = r#"{
+        "config": { "polling": { "sleep": "00:05:00" } },
+        "_links": {
+            "deploymentBase": {
+                "href": "https://hawkbit.example.com/DEFAULT/controller/v1/dev1/deploymentBase/8?c=-2129030598"
+            },
+            "configData": {
+                "href": "https://hawkbit.example.com/DEFAULT/controller/v1/dev1/configData"
+            }
+        }
+    }"#;
+
+    const CONTROLLER_CANCEL: &str = r#"{
+        "config": { "polling": { "sleep": "12:00:00" } },
+        "_links": {
+            "cancelAction": {
+                "href": "https://hawkbit.example.com/DEFAULT/controller/v1/dev1/cancelAction/11"
+            }
+        }
+    }"#;
+
+    const DEPLOYMENT_BASE: &str = r#"{
+        "id": "8",
+        "deployment": {
+            "download": "forced",
+            "update": "forced",
+            "maintenanceWindow": "available",
+            "chunks": [
+                {
+                    "part": "bApp",
+                    "version": "1.0.79",
+                    "name": "oneapp",
+                    "artifacts": [
+                        {
+                            "filename": "app.bin",
+                            "hashes": {
+                                "sha1": "5a8a3d5b0b5c4b1b1a2a8f6f3b6b6e9b0a2c2d0f",
+                                "md5": "0d1b08c34858921bc7c662b228acb7ba",
+                                "sha256": "6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b"
+                            },
+                            "size": 11,
+                            "_links": {
+                                "download": { "href": "https://hawkbit.example.com/DEFAULT/controller/v1/dev1/softwaremodules/23/artifacts/app.bin" },
+                                "md5sum": { "href": "https://hawkbit.example.com/DEFAULT/controller/v1/dev1/softwaremodules/23/artifacts/app.bin.MD5SUM" }
+                            }
+                        }
+                    ]
+                },
+                {
+                    "part": "firmware",
+                    "version": "2.1.0",
+                    "name": "device-firmware",
+                    "artifacts": [
+                        {
+                            "filename": "firmware.json",
+                            "hashes": {
+                                "sha256": "1f0e6a2b4e1d8c1e7e2b0f2c5a4d3c2b1a0f9e8d7c6b5a4f3e2d1c0b9a8f7e6d"
+                            },
+                            "size": 54,
+                            "_links": {
+                                "download-http": { "href": "http://hawkbit.example.com/DEFAULT/controller/v1/dev1/softwaremodules/24/artifacts/firmware.json" }
+                            }
+                        },
+                        {
+                            "filename": "firmware.bin",
+                            "hashes": {
+                                "sha1": "2d14d22e3b5b8c6b9f7c1cbe0d8b1bb4b9c0e58a",
+                                "md5": "bd6e1f0a87bb4bff4b0fa3c5bd59e1c7",
+                                "sha256": "9b871512327c09ce91dd649b3f96a63b7408ef267c8cc5710114e629730cb61f"
+                            },
+                            "size": 262144,
+                            "_links": {
+                                "download-http": { "href": "http://hawkbit.example.com/DEFAULT/controller/v1/dev1/softwaremodules/24/artifacts/firmware.bin" },
+                                "download": { "href": "https://hawkbit.example.com/DEFAULT/controller/v1/dev1/softwaremodules/24/artifacts/firmware.bin" }
+                            }
+                        }
+                    ]
+                }
+            ]
+        },
+        "actionHistory": {
+            "status": "RUNNING",
+            "messages": ["Reboot", "Write firmware", "Download done"]
+        }
+    }"#;
+
+    const CANCEL_ACTION: &str = r#"{
+        "id": "11",
+        "cancelAction": { "stopId": "11" }
+    }"#;
+
+    fn params(part: Option<&str>, artifact: Option<&str>) -> HawkbitParams {
+        HawkbitParams {
+            controller: "dev1".to_string(),
+            url: None,
+            tenant: None,
+            secret: None,
+            part: part.map(|s| s.to_string()),
+            artifact: artifact.map(|s| s.to_string()),
+        }
+    }
+
+    #[test]
+    fn controller_base() {
+        let base: ControllerBase = serde_json::from_str(CONTROLLER_BASE).unwrap();
+        assert!(base.links.cancel_action.is_none());
+        assert_eq!(
+            "https://hawkbit.example.com/DEFAULT/controller/v1/dev1/deploymentBase/8?c=-2129030598",
+            base.links.deployment_base.unwrap().href
+        );
+        assert_eq!(
+            Duration::from_secs(300),
+            parse_sleep(&base.config.unwrap().polling.sleep).unwrap()
+        );
+
+        let base: ControllerBase = serde_json::from_str(CONTROLLER_CANCEL).unwrap();
+        assert!(base.links.deployment_base.is_none());
+        assert!(base.links.cancel_action.is_some());
+        assert_eq!(
+            Duration::from_secs(12 * 3600),
+            parse_sleep(&base.config.unwrap().polling.sleep).unwrap()
+        );
+
+        let cancel: CancelAction = serde_json::from_str(CANCEL_ACTION).unwrap();
+        assert_eq!("11", cancel.id);
+
+        assert!(parse_sleep("5:00").is_err());
+        assert!(parse_sleep("00:0a:00").is_err());
+    }
+
+    #[test]
+    fn attribute_mapping() {
+        let mut device = Device::default();
+        device
+            .metadata
+            .labels
+            .insert("board".to_string(), "nrf52840".to_string());
+        device
+            .metadata
+            .annotations
+            .insert("hawkbit/hw".to_string(), "rev2".to_string());
+
+        // Without a mapping, only the version is registered
+        assert_eq!(
+            HashMap::from([("version".to_string(), "1.2.0".to_string())]),
+            controller_attributes(&HashMap::new(), Some(&device), b"1.2.0")
+        );
+
+        let mapping = HashMap::from([
+            ("fw".to_string(), "version".to_string()),
+            ("board".to_string(), "label:board".to_string()),
+            ("hw".to_string(), "annotation:hawkbit/hw".to_string()),
+            ("serial".to_string(), "label:serial".to_string()),
+            ("other".to_string(), "unknown".to_string()),
+        ]);
+        assert_eq!(
+            HashMap::from([
+                ("fw".to_string(), "1.2.0".to_string()),
+                ("board".to_string(), "nrf52840".to_string()),
+                ("hw".to_string(), "rev2".to_string()),
+            ]),
+            controller_attributes(&mapping, Some(&device), b"1.2.0")
+        );
+        assert_eq!(
+            HashMap::from([("fw".to_string(), "1.2.0".to_string())]),
+            controller_attributes(&mapping, None, b"1.2.0")
+        );
+    }
+
+    #[tokio::test]
+    async fn register_attributes() {
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response, Server};
+        use std::convert::Infallible;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::{Arc, Mutex};
+
+        // Stub recording the config data requests, failing them while `fail` is set
+        let requests: Arc<Mutex<Vec<(String, String, serde_json::Value)>>> = Default::default();
+        let fail = Arc::new(AtomicBool::new(false));
+        let service = {
+            let (requests, fail) = (requests.clone(), fail.clone());
+            make_service_fn(move |_| {
+                let (requests, fail) = (requests.clone(), fail.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let (requests, fail) = (requests.clone(), fail.clone());
+                        async move {
+                            let path = req.uri().path().to_string();
+                            let authorization =
+                                req.headers()["authorization"].to_str().unwrap().to_string();
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            requests.lock().unwrap().push((
+                                path,
+                                authorization,
+                                serde_json::from_slice(&body).unwrap(),
+                            ));
+                            let status = if fail.load(Ordering::SeqCst) {
+                                500
+                            } else {
+                                200
+                            };
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(status)
+                                    .body(Body::empty())
+                                    .unwrap(),
+                            )
+                        }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let mut client = HawkbitClient::new(
+            &format!("http://{}", addr),
+            "DEFAULT",
+            Some("gw1".to_string()),
+            None,
+            Vec::new(),
+            10,
+        );
+        let attributes =
+            |version: &str| HashMap::from([("version".to_string(), version.to_string())]);
+        let params = params(None, None);
+
+        client.register(&params, attributes("1.0.0")).await.unwrap();
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(1, requests.len());
+            let (path, authorization, data) = &requests[0];
+            assert_eq!("/DEFAULT/controller/v1/dev1/configData", path);
+            assert_eq!("GatewayToken gw1", authorization);
+            assert_eq!(
+                json!({"mode": "merge", "data": {"version": "1.0.0"}}),
+                *data
+            );
+        }
+
+        // Unchanged attributes are not registered again
+        client.register(&params, attributes("1.0.0")).await.unwrap();
+        assert_eq!(1, requests.lock().unwrap().len());
+
+        // Failed registrations are retried with the next status
+        fail.store(true, Ordering::SeqCst);
+        client.register(&params, attributes("1.1.0")).await.unwrap();
+        assert_eq!(2, requests.lock().unwrap().len());
+        fail.store(false, Ordering::SeqCst);
+        client.register(&params, attributes("1.1.0")).await.unwrap();
+        client.register(&params, attributes("1.1.0")).await.unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(3, requests.len());
+        assert_eq!(json!({"version": "1.1.0"}), requests[2].2["data"]);
+    }
+
+    #[test]
+    fn deployment_artifacts() {
+        let read = |part, artifact| {
+            let base: DeploymentBase = serde_json::from_str(DEPLOYMENT_BASE).unwrap();
+            read_deployment(&params(part, artifact), base)
+        };
+
+        // First artifact by default
+        let (metadata, deployment) = read(None, None).unwrap();
+        assert_eq!("8", deployment.id);
+        assert_eq!(b"1.0.79".to_vec(), metadata.version);
+        assert_eq!(
+            "6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b",
+            metadata.checksum
+        );
+        assert_eq!(11, metadata.size);
+        assert_eq!(
+            "https://hawkbit.example.com/DEFAULT/controller/v1/dev1/softwaremodules/23/artifacts/app.bin",
+            deployment.path
+        );
+
+        // By type and name
+        let (metadata, deployment) = read(Some("firmware"), Some("firmware.bin")).unwrap();
+        assert_eq!(b"2.1.0".to_vec(), metadata.version);
+        assert_eq!(
+            "9b871512327c09ce91dd649b3f96a63b7408ef267c8cc5710114e629730cb61f",
+            metadata.checksum
+        );
+        assert_eq!(262144, metadata.size);
+        assert_eq!(
+            "http://hawkbit.example.com/DEFAULT/controller/v1/dev1/softwaremodules/24/artifacts/firmware.bin",
+            deployment.path
+        );
+
+        let (_, deployment) = read(Some("firmware"), None).unwrap();
+        assert!(deployment.path.ends_with("firmware.json"));
+
+        assert!(read(Some("os"), None).is_err());
+        assert!(read(Some("bApp"), Some("firmware.bin")).is_err());
+    }
+
+    #[test]
+    fn controller_tokens() {
+        let secrets = std::env::temp_dir().join(format!("ajour-hawkbit-{}", std::process::id()));
+        std::fs::create_dir_all(secrets.join("target")).unwrap();
+        std::fs::create_dir_all(secrets.join("tenant")).unwrap();
+        std::fs::write(secrets.join("target").join("target-token"), "t0ken\n").unwrap();
+        std::fs::write(secrets.join("tenant").join("gateway-token"), "gw2").unwrap();
+
+        let client = HawkbitClient::new(
+            "https://hawkbit.example.com/",
+            "DEFAULT",
+            Some("gw1".to_string()),
+            Some(secrets.clone()),
+            vec!["https://hawkbit.customer.example.com/".to_string()],
+            10,
+        );
+        let params = |url: Option<&str>, tenant: Option<&str>, secret: Option<&str>| {
+            let mut params = params(None, None);
+            params.url = url.map(|s| s.to_string());
+            params.tenant = tenant.map(|s| s.to_string());
+            params.secret = secret.map(|s| s.to_string());
+            params
+        };
+
+        let controller = client.controller(&params(None, None, None)).unwrap();
+        assert_eq!(
+            "https://hawkbit.example.com/DEFAULT/controller/v1/dev1",
+            controller.url
+        );
+        assert_eq!("GatewayToken gw1", controller.authorization);
+
+        let controller = client
+            .controller(&params(None, None, Some("target")))
+            .unwrap();
+        assert_eq!("TargetToken t0ken", controller.authorization);
+
+        let controller = client
+            .controller(&params(None, Some("customer"), Some("tenant")))
+            .unwrap();
+        assert_eq!(
+            "https://hawkbit.example.com/customer/controller/v1/dev1",
+            controller.url
+        );
+        assert_eq!("GatewayToken gw2", controller.authorization);
+
+        // Gateway token of the store is not used for other tenants
+        assert!(client
+            .controller(&params(None, Some("customer"), None))
+            .is_err());
+        assert!(client
+            .controller(&params(Some("https://other.example.com"), None, None))
+            .is_err());
+
+        let controller = client
+            .controller(&params(
+                Some("https://hawkbit.customer.example.com"),
+                Some("customer"),
+                Some("tenant"),
+            ))
+            .unwrap();
+        assert_eq!(
+            "https://hawkbit.customer.example.com/customer/controller/v1/dev1",
+            controller.url
+        );
+        assert_eq!("GatewayToken gw2", controller.authorization);
+
+        // Tokens are not sent to instances that are not configured
+        assert!(client
+            .controller(&params(
+                Some("https://other.example.com"),
+                Some("customer"),
+                Some("tenant")
+            ))
+            .is_err());
+
+        // Secrets outside of the secrets path, and tenants changing the path
+        std::fs::write(secrets.join("target-token"), "outside").unwrap();
+        for secret in ["..", "../ajour-hawkbit", "target/..", "/etc"] {
+            assert!(
+                client
+                    .controller(&params(None, None, Some(secret)))
+                    .is_err(),
+                "{}",
+                secret
+            );
+        }
+        for tenant in ["..", "customer/../DEFAULT", "customer?x=1", ""] {
+            assert!(
+                client
+                    .controller(&params(None, Some(tenant), Some("tenant")))
+                    .is_err(),
+                "{}",
+                tenant
+            );
+        }
+
+        std::fs::remove_dir_all(&secrets).unwrap();
+    }
+
+    #[test]
+    fn controller_templates() {
+        let mut device = Device::default();
+        device
+            .metadata
+            .labels
+            .insert("serial".to_string(), "A1234".to_string());
+        device
+            .metadata
+            .annotations
+            .insert("hawkbit/controller".to_string(), "ctrl-7".to_string());
+        let expand = |template| controller_id(template, "my-app", "dev1", Some(&device));
+
+        assert_eq!("dev1", expand("dev1").unwrap());
+        assert_eq!("my-app-dev1", expand("{application}-{device}").unwrap());
+        assert_eq!("board-A1234", expand("board-{label:serial}").unwrap());
+        assert_eq!("ctrl-7", expand("{annotation:hawkbit/controller}").unwrap());
+
+        assert!(expand("{label:missing}").is_err());
+        assert!(expand("{unknown}").is_err());
+        assert!(expand("{device").is_err());
+        assert!(controller_id("{label:serial}", "my-app", "dev1", None).is_err());
+
+        // Values must not change the resource path
+        device.metadata.labels.insert(
+            "path".to_string(),
+            "../../other/controller/v1/dev2".to_string(),
+        );
+        device
+            .metadata
+            .annotations
+            .insert("query".to_string(), "dev1?x=1".to_string());
+        let expand = |template| controller_id(template, "my-app", "dev1", Some(&device));
+        assert!(expand("{label:path}").is_err());
+        assert!(expand("{annotation:query}").is_err());
+        assert!(expand("dev/{device}").is_err());
+        assert!(controller_id("{device}", "my-app", "dev 1", None).is_err());
+    }
+
+    #[test]
+    fn deployment_modes() {
+        let read = |download, update, window: Option<&str>| {
+            let base = format!(
+                r#"{{
+                    "id": "9",
+                    "deployment": {{
+                        "download": "{}",
+                        "update": "{}",
+                        {}
+                        "chunks": [{{
+                            "part": "firmware",
+                            "version": "2.1.0",
+                            "name": "device-firmware",
+                            "artifacts": [{{
+                                "filename": "firmware.bin",
+                                "hashes": {{ "sha256": "9b871512327c09ce91dd649b3f96a63b7408ef267c8cc5710114e629730cb61f" }},
+                                "size": 262144,
+                                "_links": {{ "download-http": {{ "href": "http://hawkbit.example.com/firmware.bin" }} }}
+                            }}]
+                        }}]
+                    }}
+                }}"#,
+                download,
+                update,
+                window
+                    .map(|w| format!(r#""maintenanceWindow": "{}","#, w))
+                    .unwrap_or_default()
+            );
+            let base: DeploymentBase = serde_json::from_str(&base).unwrap();
+            read_deployment(&params(None, None), base).unwrap().1
+        };
+
+        let d = read("forced", "forced", None);
+        assert!(!d.hold_download() && !d.hold_swap());
+
+        let d = read("attempt", "attempt", Some("available"));
+        assert!(!d.hold_download() && !d.hold_swap());
+
+        // Download only
+        let d = read("forced", "skip", None);
+        assert!(!d.hold_download() && d.hold_swap() && d.download_only());
+
+        // Outside the maintenance window
+        let d = read("forced", "skip", Some("unavailable"));
+        assert!(!d.hold_download() && d.hold_swap() && !d.download_only());
+
+        let d = read("skip", "skip", Some("unavailable"));
+        assert!(d.hold_download() && d.hold_swap());
+    }
+}
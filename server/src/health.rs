@@ -1,17 +1,25 @@
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub struct HealthServer {
     port: u16,
+    ready: Arc<AtomicBool>,
 }
 impl HealthServer {
-    pub fn new(port: u16) -> Self {
-        Self { port }
+    /// Serve health status, reporting not ready until `ready` is set.
+    pub fn new(port: u16, ready: Arc<AtomicBool>) -> Self {
+        Self { port, ready }
     }
 
     pub async fn run(&mut self) -> Result<(), anyhow::Error> {
         let addr = ([0, 0, 0, 0], self.port).into();
-        let service = make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(healthz)) });
+        let ready = self.ready.clone();
+        let service = make_service_fn(move |_| {
+            let ready = ready.clone();
+            async move { Ok::<_, hyper::Error>(service_fn(move |req| healthz(req, ready.clone()))) }
+        });
 
         let server = Server::bind(&addr).serve(service);
 
@@ -22,8 +30,16 @@ impl HealthServer {
     }
 }
 
-async fn healthz(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    match (req.method(), req.uri().path()) {
-        (_, _) => Ok(Response::new(Body::from("{\"status\": \"OK\"}"))),
+/// Liveness on any path, readiness on `/readyz`.
+async fn healthz(
+    req: Request<Body>,
+    ready: Arc<AtomicBool>,
+) -> Result<Response<Body>, hyper::Error> {
+    if req.uri().path() != "/readyz" || ready.load(Ordering::Relaxed) {
+        Ok(Response::new(Body::from("{\"status\": \"OK\"}")))
+    } else {
+        let mut response = Response::new(Body::from("{\"status\": \"NOT_READY\"}"));
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        Ok(response)
     }
 }
@@ -0,0 +1,421 @@
+use crate::file::FileMetadata;
+use crate::metadata::Metadata;
+use crate::updater::FirmwareStore;
+use crate::validate;
+use anyhow::anyhow;
+use lru::LruCache;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ETAG, IF_NONE_MATCH};
+use reqwest::{redirect, StatusCode};
+use std::path::PathBuf;
+
+/// Firmware store for metadata and binaries published on HTTP servers.
+pub struct HttpClient {
+    client: reqwest::Client,
+    // URL prefixes that firmware may be fetched from
+    urls: Vec<String>,
+    // Directory of secrets, each a directory of header name files
+    secrets: Option<PathBuf>,
+    // URL prefixes that secrets may be sent to
+    secret_urls: Vec<String>,
+
+    // Cached by URL, along with the ETag
+    metadata_cache: LruCache<String, (String, FileMetadata)>,
+    // Cached by checksum, which is revalidated with the metadata
+    firmware_cache: LruCache<String, Vec<u8>>,
+}
+
+/// Metadata URL, binary URL and secret.
+pub type HttpParams = (String, String, Option<String>);
+
+impl HttpClient {
+    pub fn new(
+        urls: Vec<String>,
+        secrets: Option<PathBuf>,
+        secret_urls: Vec<String>,
+        cache_size: usize,
+    ) -> Result<Self, anyhow::Error> {
+        // Redirects must stay within the allowed URLs as well
+        let allowed = urls.clone();
+        let client = reqwest::Client::builder()
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= 10 {
+                    attempt.error("too many redirects")
+                } else if validate::url_within(attempt.url().as_str(), &allowed) {
+                    attempt.follow()
+                } else {
+                    let error =
+                        format!("redirect to {} is not within the store URLs", attempt.url());
+                    attempt.error(error)
+                }
+            }))
+            .build()?;
+        Ok(Self {
+            client,
+            urls,
+            secrets,
+            secret_urls,
+            metadata_cache: LruCache::new(cache_size),
+            firmware_cache: LruCache::new(cache_size),
+        })
+    }
+
+    /// Read request headers from a secret for a URL, with one file per header.
+    fn headers(&self, secret: &Option<String>, url: &str) -> Result<HeaderMap, anyhow::Error> {
+        let mut headers = HeaderMap::new();
+        if let Some(secret) = secret {
+            let secret = validate::file_name(secret)?;
+            // The URL comes from the spec as well, only send secrets where they belong
+            if !validate::url_within(url, &self.secret_urls) {
+                return Err(anyhow!(
+                    "Secret {} may not be sent to {}, it is not within the secret URLs of the store",
+                    secret,
+                    url
+                ));
+            }
+            let path = self
+                .secrets
+                .as_ref()
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Firmware requires secret {}, but no secrets path configured",
+                        secret
+                    )
+                })?
+                .join(secret);
+            for entry in std::fs::read_dir(&path)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                // Skip the bookkeeping entries of mounted Kubernetes secrets
+                if name.starts_with('.') || !entry.path().is_file() {
+                    continue;
+                }
+                let value = std::fs::read_to_string(entry.path())?;
+                headers.insert(
+                    HeaderName::from_bytes(name.as_bytes())?,
+                    HeaderValue::from_str(value.trim())?,
+                );
+            }
+        }
+        Ok(headers)
+    }
+
+    /// Fetch a URL, returning None if it is unchanged since `etag`.
+    async fn fetch(
+        &self,
+        url: &str,
+        headers: HeaderMap,
+        etag: Option<&String>,
+    ) -> Result<Option<(Option<String>, Vec<u8>)>, anyhow::Error> {
+        // URLs come from the device spec, only fetch from where the store allows
+        if !validate::url_within(url, &self.urls) {
+            return Err(anyhow!("{} is not within the URLs of the store", url));
+        }
+        let mut request = self.client.get(url).headers(headers);
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            log::debug!("{} not modified", url);
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        Ok(Some((etag, response.bytes().await?.to_vec())))
+    }
+}
+
+#[async_trait::async_trait]
+impl FirmwareStore for HttpClient {
+    type Params = HttpParams;
+
+    async fn fetch_metadata(
+        &mut self,
+        params: &Self::Params,
+    ) -> Result<(Self::Context, Option<Metadata>), anyhow::Error> {
+        let (url, _, secret) = params;
+        let headers = self.headers(secret, url)?;
+        let cached = self.metadata_cache.get(url).cloned();
+        let metadata = match self
+            .fetch(url, headers, cached.as_ref().map(|c| &c.0))
+            .await?
+        {
+            Some((etag, data)) => {
+                let metadata: FileMetadata = serde_json::from_slice(&data)?;
+                if let Some(etag) = etag {
+                    self.metadata_cache
+                        .put(url.clone(), (etag, metadata.clone()));
+                }
+                metadata
+            }
+            None => cached
+                .map(|c| c.1)
+                .ok_or_else(|| anyhow!("Unexpected response for uncached metadata {}", url))?,
+        };
+        Ok(((), Some(metadata.into())))
+    }
+
+    async fn update_progress(
+        &mut self,
+        _: &Self::Params,
+        _: &Self::Context,
+        _: u32,
+        _: u32,
+        _: &str,
+    ) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    async fn mark_synced(
+        &mut self,
+        _: &Self::Params,
+        _: &Self::Context,
+        _: bool,
+    ) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &mut self,
+        _: &Self::Params,
+        _: &Self::Context,
+        _: &str,
+    ) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    type Context = ();
+    async fn fetch_firmware(
+        &mut self,
+        params: &Self::Params,
+        _: &Self::Context,
+        metadata: &Metadata,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let (_, url, secret) = params;
+        if let Some(data) = self.firmware_cache.get(&metadata.checksum) {
+            return Ok(data.clone());
+        }
+        let headers = self.headers(secret, url)?;
+        let (_, data) = self
+            .fetch(url, headers, None)
+            .await?
+            .ok_or_else(|| anyhow!("Unexpected response for firmware {}", url))?;
+        metadata.verify_checksum(&data)?;
+        self.firmware_cache
+            .put(metadata.checksum.clone(), data.clone());
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const ETAG_V1: &str = "\"v1\"";
+    const METADATA: &str = r#"{"version":"1.0.0","checksum":"9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a","size":4}"#;
+
+    async fn stub(requests: Arc<AtomicUsize>) -> SocketAddr {
+        let service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let requests = requests.clone();
+                    async move {
+                        if req.headers().get("authorization").map(|v| v.as_bytes())
+                            != Some(b"Bearer secret")
+                        {
+                            return Ok::<_, Infallible>(
+                                Response::builder().status(401).body(Body::empty()).unwrap(),
+                            );
+                        }
+                        if req.headers().get("if-none-match").map(|v| v.as_bytes())
+                            == Some(ETAG_V1.as_bytes())
+                        {
+                            return Ok(Response::builder()
+                                .status(304)
+                                .body(Body::empty())
+                                .unwrap());
+                        }
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        let body = match req.uri().path() {
+                            "/firmware.json" => Body::from(METADATA),
+                            "/firmware.bin" => Body::from(vec![1, 2, 3, 4]),
+                            "/redirect.bin" => {
+                                return Ok(Response::builder()
+                                    .status(302)
+                                    .header("location", "http://127.0.0.1:1/firmware.bin")
+                                    .body(Body::empty())
+                                    .unwrap())
+                            }
+                            _ => {
+                                return Ok(Response::builder()
+                                    .status(404)
+                                    .body(Body::empty())
+                                    .unwrap())
+                            }
+                        };
+                        Ok(Response::builder()
+                            .header("etag", ETAG_V1)
+                            .body(body)
+                            .unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn secrets() -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ajour-http-test-{}", std::process::id()));
+        std::fs::create_dir_all(path.join("token")).unwrap();
+        std::fs::write(path.join("token").join("Authorization"), "Bearer secret\n").unwrap();
+        path
+    }
+
+    fn http_client(
+        addr: SocketAddr,
+        secrets: Option<PathBuf>,
+        secret_urls: Vec<String>,
+    ) -> HttpClient {
+        HttpClient::new(vec![format!("http://{}/", addr)], secrets, secret_urls, 10).unwrap()
+    }
+
+    #[tokio::test]
+    async fn fetch_with_etag() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let addr = stub(requests.clone()).await;
+        let params: HttpParams = (
+            format!("http://{}/firmware.json", addr),
+            format!("http://{}/firmware.bin", addr),
+            Some("token".to_string()),
+        );
+
+        let mut client = http_client(addr, Some(secrets()), vec![format!("http://{}/", addr)]);
+        for _ in 0..2 {
+            let (_, metadata) = client.fetch_metadata(&params).await.unwrap();
+            let metadata = metadata.unwrap();
+            assert_eq!(b"1.0.0".to_vec(), metadata.version);
+            assert_eq!(4, metadata.size);
+
+            // Once per block
+            for _ in 0..3 {
+                let firmware = client
+                    .fetch_firmware(&params, &(), &metadata)
+                    .await
+                    .unwrap();
+                assert_eq!(vec![1, 2, 3, 4], firmware);
+            }
+        }
+
+        // The firmware is fetched once, and the metadata revalidated in the second round
+        assert_eq!(2, requests.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn unauthorized() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let addr = stub(requests).await;
+        let params: HttpParams = (
+            format!("http://{}/firmware.json", addr),
+            format!("http://{}/firmware.bin", addr),
+            None,
+        );
+
+        let mut client = http_client(addr, None, Vec::new());
+        assert!(client.fetch_metadata(&params).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn url_restrictions() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let addr = stub(requests.clone()).await;
+        let mut client = http_client(addr, Some(secrets()), vec![format!("http://{}/", addr)]);
+        let (_, metadata) = client
+            .fetch_metadata(&(
+                format!("http://{}/firmware.json", addr),
+                String::new(),
+                Some("token".to_string()),
+            ))
+            .await
+            .unwrap();
+        let metadata = metadata.unwrap();
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+
+        // Not within the store URLs
+        for url in [
+            "http://127.0.0.1:1/firmware.json".to_string(),
+            format!("https://{}/firmware.json", addr),
+            "file:///etc/passwd".to_string(),
+        ] {
+            let params: HttpParams = (url.clone(), url.clone(), None);
+            assert!(client.fetch_metadata(&params).await.is_err(), "{}", url);
+            assert!(
+                client
+                    .fetch_firmware(&params, &(), &metadata)
+                    .await
+                    .is_err(),
+                "{}",
+                url
+            );
+        }
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+
+        // Redirected outside of the store URLs
+        let params: HttpParams = (
+            format!("http://{}/firmware.json", addr),
+            format!("http://{}/redirect.bin", addr),
+            Some("token".to_string()),
+        );
+        assert!(client
+            .fetch_firmware(&params, &(), &metadata)
+            .await
+            .is_err());
+        assert_eq!(2, requests.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn secret_restrictions() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let addr = stub(requests.clone()).await;
+        let secrets = secrets();
+        std::fs::write(secrets.join("outside"), "Bearer other").unwrap();
+        let mut client = http_client(
+            addr,
+            Some(secrets.join("token")),
+            vec![format!("http://{}/", addr)],
+        );
+
+        // Secrets outside of the secrets path
+        for secret in ["../outside", "..", "/etc", "token/../../outside"] {
+            let params: HttpParams = (
+                format!("http://{}/firmware.json", addr),
+                format!("http://{}/firmware.bin", addr),
+                Some(secret.to_string()),
+            );
+            assert!(client.fetch_metadata(&params).await.is_err(), "{}", secret);
+        }
+
+        // Secrets sent elsewhere
+        let mut client = http_client(addr, Some(secrets), vec!["http://example.com/".to_string()]);
+        let params: HttpParams = (
+            format!("http://{}/firmware.json", addr),
+            format!("http://{}/firmware.bin", addr),
+            Some("token".to_string()),
+        );
+        assert!(client.fetch_metadata(&params).await.is_err());
+        assert_eq!(0, requests.load(Ordering::SeqCst));
+    }
+}
@@ -1,14 +1,294 @@
 use ajour_schema::*;
-use drogue_client::{core::v1::ConditionStatus, Translator};
+use drogue_client::{
+    core::v1::ConditionStatus,
+    error::ClientError,
+    registry::v1::{Application, Device},
+    Translator,
+};
+use lru::LruCache;
+use reqwest::StatusCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::metadata::Metadata;
+use crate::metadata::{Metadata, Verification};
+use crate::telemetry;
 use embedded_update::Status;
 
 pub type DrogueClient = drogue_client::registry::v1::Client;
 
+/// Registry operations used by the index.
+#[async_trait::async_trait]
+pub trait Registry {
+    async fn get_app(&self, application: &str) -> Result<Option<Application>, ClientError>;
+
+    async fn get_device(
+        &self,
+        application: &str,
+        device: &str,
+    ) -> Result<Option<Device>, ClientError>;
+
+    async fn update_device(&self, device: &Device) -> Result<bool, ClientError>;
+}
+
+#[async_trait::async_trait]
+impl Registry for DrogueClient {
+    async fn get_app(&self, application: &str) -> Result<Option<Application>, ClientError> {
+        DrogueClient::get_app(self, application).await
+    }
+
+    async fn get_device(
+        &self,
+        application: &str,
+        device: &str,
+    ) -> Result<Option<Device>, ClientError> {
+        DrogueClient::get_device(self, application, device).await
+    }
+
+    async fn update_device(&self, device: &Device) -> Result<bool, ClientError> {
+        DrogueClient::update_device(self, device).await
+    }
+}
+
 #[derive(Clone)]
-pub struct Index {
-    client: DrogueClient,
+pub struct Index<R = DrogueClient> {
+    client: R,
+    cache: Arc<Mutex<Cache>>,
+    policy: StatusPolicy,
+}
+
+/// Cache of registry objects, entries expire after a TTL or when a newer resourceVersion is observed.
+///
+/// The firmware of a device depends on both the device and its application, so a device changed
+/// by someone else also evicts its application.
+struct Cache {
+    ttl: Duration,
+    devices: LruCache<(String, String), Entry<Device>>,
+    applications: LruCache<String, Entry<Application>>,
+    // Time of the last status write per device
+    written: LruCache<(String, String), Instant>,
+}
+
+struct Entry<T> {
+    fetched: Instant,
+    value: Option<T>,
+    // False if we've written the object since, and the resourceVersion is outdated
+    current: bool,
+}
+
+impl<T: Clone> Entry<T> {
+    fn get(&self, ttl: Duration) -> Option<Option<T>> {
+        if self.fetched.elapsed() < ttl {
+            Some(self.value.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl Cache {
+    fn new(ttl: Duration, size: usize) -> Self {
+        Self {
+            ttl,
+            devices: LruCache::new(size),
+            applications: LruCache::new(size),
+            written: LruCache::new(size),
+        }
+    }
+
+    fn device(
+        &mut self,
+        application: &str,
+        device: &str,
+        refresh: bool,
+    ) -> Option<(Option<Device>, bool)> {
+        let ttl = self.ttl;
+        self.devices
+            .get(&(application.to_string(), device.to_string()))
+            .filter(|e| e.current || !refresh)
+            .and_then(|e| e.get(ttl).map(|d| (d, e.current)))
+    }
+
+    fn put_device(&mut self, application: &str, name: &str, device: Option<Device>) {
+        let key = (application.to_string(), name.to_string());
+        let changed = match (self.devices.peek(&key), &device) {
+            // Our own status writes keep the generation, and leave the entry outdated
+            (
+                Some(Entry {
+                    value: Some(old),
+                    current,
+                    ..
+                }),
+                Some(new),
+            ) => {
+                old.metadata.generation != new.metadata.generation
+                    || (*current && old.metadata.resource_version != new.metadata.resource_version)
+            }
+            (Some(Entry { value: old, .. }), new) => old.is_some() != new.is_some(),
+            (None, _) => false,
+        };
+        if changed {
+            log::debug!(
+                "Device {}/{} changed, refreshing application",
+                application,
+                name
+            );
+            self.applications.pop(application);
+        }
+        self.devices.put(
+            key,
+            Entry {
+                fetched: Instant::now(),
+                value: device,
+                current: true,
+            },
+        );
+    }
+
+    /// Keep a device we've just written, its resourceVersion must be refreshed before the next write.
+    fn put_written_device(&mut self, application: &str, name: &str, device: Device) {
+        let key = (application.to_string(), name.to_string());
+        self.written.put(key.clone(), Instant::now());
+        self.devices.put(
+            key,
+            Entry {
+                fetched: Instant::now(),
+                value: Some(device),
+                current: false,
+            },
+        );
+    }
+
+    /// Evict a device changed by someone else, along with its application.
+    fn invalidate_device(&mut self, application: &str, device: &str) {
+        self.devices
+            .pop(&(application.to_string(), device.to_string()));
+        self.applications.pop(application);
+    }
+
+    fn last_written(&mut self, application: &str, device: &str) -> Option<Instant> {
+        self.written
+            .get(&(application.to_string(), device.to_string()))
+            .cloned()
+    }
+
+    fn application(&mut self, application: &str) -> Option<Option<Application>> {
+        let ttl = self.ttl;
+        self.applications.get(application).and_then(|e| e.get(ttl))
+    }
+
+    fn put_application(&mut self, name: &str, application: Option<Application>) {
+        self.applications.put(
+            name.to_string(),
+            Entry {
+                fetched: Instant::now(),
+                value: application,
+                current: true,
+            },
+        );
+    }
+}
+
+/// A component of a device with multiple firmware components.
+pub struct Component<'a> {
+    pub name: &'a str,
+    /// All components of the device, in update order
+    pub all: &'a [String],
+}
+
+/// Decides when a firmware status change is worth writing to the registry.
+#[derive(Debug, Clone)]
+pub struct StatusPolicy {
+    /// Write when update progress crosses a multiple of this many percent
+    pub progress_step: f32,
+    /// Write progress at least this often
+    pub interval: Duration,
+    /// Number of times to retry a write on resourceVersion conflicts
+    pub conflict_retries: usize,
+}
+
+impl StatusPolicy {
+    fn needs_write(
+        &self,
+        old: &FirmwareStatus,
+        new: &FirmwareStatus,
+        last_written: Option<Instant>,
+    ) -> bool {
+        if old.current != new.current || old.target != new.target {
+            return true;
+        }
+
+        // Condition transitions, ignoring progress
+        if condition_states(old) != condition_states(new) {
+            return true;
+        }
+
+        match (progress(old), progress(new)) {
+            (Some(o), Some(n)) => {
+                // Completed transfers are always reported
+                if n >= 100.0 && o != n {
+                    return true;
+                }
+                if self.progress_step > 0.0
+                    && (o / self.progress_step).floor() != (n / self.progress_step).floor()
+                {
+                    return true;
+                }
+                o != n
+                    && last_written
+                        .map(|w| w.elapsed() >= self.interval)
+                        .unwrap_or(true)
+            }
+            (o, n) => o != n,
+        }
+    }
+}
+
+const UPDATE_PROGRESS: &str = "UpdateProgress";
+const SIGNATURE_VERIFIED: &str = "SignatureVerified";
+const UPDATE_FAILED: &str = "UpdateFailed";
+
+fn condition_states(
+    status: &FirmwareStatus,
+) -> Vec<(String, String, Option<String>, Option<String>)> {
+    let mut states: Vec<_> = status
+        .conditions
+        .iter()
+        .map(|c| {
+            let message = if c.r#type == UPDATE_PROGRESS {
+                None
+            } else {
+                c.message.clone()
+            };
+            (
+                c.r#type.clone(),
+                c.status.clone(),
+                c.reason.clone(),
+                message,
+            )
+        })
+        .collect();
+    states.sort();
+    states
+}
+
+fn progress(status: &FirmwareStatus) -> Option<f32> {
+    status
+        .conditions
+        .iter()
+        .find(|c| c.r#type == UPDATE_PROGRESS)
+        .and_then(|c| c.message.as_ref())
+        .and_then(|m| m.parse().ok())
+}
+
+fn in_sync(status: &FirmwareStatus) -> bool {
+    status
+        .conditions
+        .iter()
+        .any(|c| c.r#type == "InSync" && c.status == "True")
+}
+
+fn is_conflict(e: &ClientError) -> bool {
+    matches!(e, ClientError::Service { code, .. } if *code == StatusCode::CONFLICT)
 }
 
 fn update_status(fwstatus: &mut FirmwareStatus, status: &Status, data: Result<&Metadata, String>) {
@@ -29,7 +309,7 @@ fn update_status(fwstatus: &mut FirmwareStatus, status: &Status, data: Result<&M
                 if let Some(update) = &status.update {
                     let progress = 100.0 * (update.offset as f32 / metadata.size as f32);
                     fwstatus.conditions.update(
-                        "UpdateProgress",
+                        UPDATE_PROGRESS,
                         ConditionStatus {
                             message: Some(format!("{:.2}", progress)),
                             ..Default::default()
@@ -37,9 +317,27 @@ fn update_status(fwstatus: &mut FirmwareStatus, status: &Status, data: Result<&M
                     );
                 }
             }
+
+            match &metadata.verification {
+                Some(Verification::Verified) => {
+                    fwstatus.conditions.update(SIGNATURE_VERIFIED, true);
+                }
+                Some(Verification::Failed(reason)) | Some(Verification::Rejected(reason)) => {
+                    fwstatus.conditions.update(
+                        SIGNATURE_VERIFIED,
+                        ConditionStatus {
+                            status: Some(false),
+                            message: Some("Firmware signature verification failed".to_string()),
+                            reason: Some(reason.clone()),
+                        },
+                    );
+                }
+                None => {}
+            }
         }
         Err(error) => {
-            fwstatus.conditions.clear();
+            // Repeated failures are tracked by the updater
+            fwstatus.conditions.0.retain(|c| c.r#type == UPDATE_FAILED);
             fwstatus.current = core::str::from_utf8(&status.version)
                 .unwrap_or("Unknown")
                 .to_string();
@@ -50,32 +348,37 @@ fn update_status(fwstatus: &mut FirmwareStatus, status: &Status, data: Result<&M
                     status: Some(false),
                     message: Some("Error retrieving firmware metadata".to_string()),
                     reason: Some(error),
-                    ..Default::default()
                 },
             );
         }
     }
 }
 
-impl Index {
-    pub fn new(client: DrogueClient) -> Self {
-        Self { client }
-    }
-    pub async fn latest_version(
-        &self,
-        application: &str,
-        device: &str,
-    ) -> Result<Option<FirmwareSpec>, anyhow::Error> {
+/// A device and its application, as found in the registry.
+pub struct Resources {
+    pub device: Option<Device>,
+    pub application: Option<Application>,
+}
+
+impl Resources {
+    /// Look up the firmware of the device, falling back to the rules and spec of the application.
+    pub fn latest_version(&self) -> Result<Option<FirmwareSpec>, anyhow::Error> {
         // Check if we got a device on the device first
-        if let Some(device) = self.client.get_device(application, device).await? {
+        if let Some(device) = &self.device {
             if let Some(spec) = device.section::<FirmwareSpec>() {
                 return Ok(Some(spec?));
             }
         }
 
-        let app = self.client.get_app(application).await?;
-        if let Some(app) = app {
-            // Check if we've got a device spec first;
+        if let Some(app) = &self.application {
+            // Check if any of the application rules match the device labels
+            if let (Some(device), Some(rules)) = (&self.device, app.section::<FirmwareRules>()) {
+                if let Some(spec) = rules?.select(&device.metadata.labels) {
+                    return Ok(Some(spec.clone()));
+                }
+            }
+
+            // Fall back to the application spec
             if let Some(spec) = app.section::<FirmwareSpec>() {
                 return Ok(Some(spec?));
             }
@@ -83,23 +386,689 @@ impl Index {
         Ok(None)
     }
 
+    /// Look up the firmware of a release channel of the application.
+    pub fn channel(&self, channel: &str) -> Result<Option<FirmwareSpec>, anyhow::Error> {
+        if let Some(app) = &self.application {
+            if let Some(channels) = app.section::<FirmwareChannels>() {
+                return Ok(channels?.0.remove(channel));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Look up a label of the device.
+    pub fn device_label(&self, label: &str) -> Option<String> {
+        self.device
+            .as_ref()
+            .and_then(|d| d.metadata.labels.get(label).cloned())
+    }
+
+    /// Check if all the given components of the device are in sync.
+    pub fn components_in_sync(&self, components: &[String]) -> Result<bool, anyhow::Error> {
+        let status: FirmwareStatus = self
+            .device
+            .as_ref()
+            .and_then(|d| d.section::<FirmwareStatus>())
+            .transpose()?
+            .unwrap_or_default();
+        Ok(components
+            .iter()
+            .all(|c| status.components.get(c).map(in_sync).unwrap_or(false)))
+    }
+}
+
+impl<R: Registry> Index<R> {
+    /// Create an index caching registry lookups for `ttl`, a zero `ttl` disables caching.
+    pub fn new(client: R, ttl: Duration, cache_size: usize, policy: StatusPolicy) -> Self {
+        Self {
+            client,
+            cache: Arc::new(Mutex::new(Cache::new(ttl, cache_size))),
+            policy,
+        }
+    }
+
+    /// Get a device and whether its resourceVersion is current, `refresh` skips outdated cache entries.
+    async fn get_device(
+        &self,
+        application: &str,
+        device: &str,
+        refresh: bool,
+    ) -> Result<(Option<Device>, bool), anyhow::Error> {
+        if let Some(cached) = self
+            .cache
+            .lock()
+            .unwrap()
+            .device(application, device, refresh)
+        {
+            return Ok(cached);
+        }
+        let fetched = self.client.get_device(application, device).await?;
+        self.cache
+            .lock()
+            .unwrap()
+            .put_device(application, device, fetched.clone());
+        Ok((fetched, true))
+    }
+
+    async fn get_app(&self, application: &str) -> Result<Option<Application>, anyhow::Error> {
+        if let Some(cached) = self.cache.lock().unwrap().application(application) {
+            return Ok(cached);
+        }
+        let fetched = self.client.get_app(application).await?;
+        self.cache
+            .lock()
+            .unwrap()
+            .put_application(application, fetched.clone());
+        Ok(fetched)
+    }
+
+    /// Look up a device and its application, once for all the lookups while processing its status.
+    pub async fn resources(
+        &self,
+        application: &str,
+        device: &str,
+    ) -> Result<Resources, anyhow::Error> {
+        telemetry::traced(
+            "Index::resources",
+            telemetry::device_attributes(application, device),
+            async {
+                // The device first, a changed device refreshes the application
+                let (device, _) = self.get_device(application, device, false).await?;
+                let application = self.get_app(application).await?;
+                Ok(Resources {
+                    device,
+                    application,
+                })
+            },
+        )
+        .await
+    }
+
     pub async fn update_status(
         &self,
         application: &str,
         device: &str,
+        component: Option<&Component<'_>>,
         status: &Status<'_>,
         data: Result<&Metadata, String>,
     ) -> Result<(), anyhow::Error> {
-        if let Some(mut device) = self.client.get_device(application, device).await? {
-            let mut s: FirmwareStatus = device
-                .section::<FirmwareStatus>()
-                .unwrap_or(Ok(Default::default()))?;
+        self.modify_status("Index::update_status", application, device, |s| {
+            if let Some(c) = component {
+                let entry = s.components.entry(c.name.to_string()).or_default();
+                update_status(entry, status, data.clone());
+
+                // The device is in sync when all its components are
+                let all_in_sync = c
+                    .all
+                    .iter()
+                    .all(|n| s.components.get(n).map(in_sync).unwrap_or(false));
+                s.conditions.update("InSync", all_in_sync);
+            } else {
+                update_status(s, status, data.clone());
+            }
+        })
+        .await
+    }
+
+    /// Set or clear the condition marking that updates of a device keep failing.
+    pub async fn set_update_failed(
+        &self,
+        application: &str,
+        device: &str,
+        reason: Option<String>,
+    ) -> Result<(), anyhow::Error> {
+        self.modify_status(
+            "Index::set_update_failed",
+            application,
+            device,
+            |s| match &reason {
+                Some(reason) => s.conditions.update(
+                    UPDATE_FAILED,
+                    ConditionStatus {
+                        status: Some(true),
+                        message: Some("Update failed repeatedly".to_string()),
+                        reason: Some(reason.clone()),
+                    },
+                ),
+                None => s.conditions.0.retain(|c| c.r#type != UPDATE_FAILED),
+            },
+        )
+        .await
+    }
+
+    /// Apply a change to the firmware status of a device, writing it if the change is significant.
+    async fn modify_status(
+        &self,
+        name: &'static str,
+        application: &str,
+        device: &str,
+        modify: impl Fn(&mut FirmwareStatus),
+    ) -> Result<(), anyhow::Error> {
+        telemetry::traced(
+            name,
+            telemetry::device_attributes(application, device),
+            async {
+                let mut attempt = 0;
+                let mut refresh = false;
+                loop {
+                    let (d, current) = self.get_device(application, device, refresh).await?;
+                    let mut d = match d {
+                        Some(d) => d,
+                        None => break,
+                    };
+                    let previous: FirmwareStatus = d
+                        .section::<FirmwareStatus>()
+                        .unwrap_or(Ok(Default::default()))?;
+
+                    let mut s = previous.clone();
+                    modify(&mut s);
+
+                    let last_written = self.cache.lock().unwrap().last_written(application, device);
+                    let write = self.policy.needs_write(&previous, &s, last_written)
+                        || s.components.iter().any(|(n, c)| {
+                            let old = previous.components.get(n).cloned().unwrap_or_default();
+                            self.policy.needs_write(&old, c, last_written)
+                        });
+                    if !write {
+                        log::trace!(
+                            "No significant status change for {}/{}",
+                            application,
+                            device
+                        );
+                        break;
+                    }
+
+                    // Writing requires the current resourceVersion
+                    if !current {
+                        refresh = true;
+                        continue;
+                    }
+
+                    d.set_section::<FirmwareStatus>(s)?;
+                    match self.client.update_device(&d).await {
+                        Ok(_) => {
+                            self.cache
+                                .lock()
+                                .unwrap()
+                                .put_written_device(application, device, d);
+                            break;
+                        }
+                        Err(e) => {
+                            self.cache
+                                .lock()
+                                .unwrap()
+                                .invalidate_device(application, device);
+                            if is_conflict(&e) && attempt < self.policy.conflict_retries {
+                                attempt += 1;
+                                refresh = true;
+                                log::debug!(
+                                    "Conflict updating status of {}/{}, retrying ({}/{})",
+                                    application,
+                                    device,
+                                    attempt,
+                                    self.policy.conflict_retries
+                                );
+                            } else {
+                                return Err(e.into());
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            },
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use drogue_client::error::ErrorInformation;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    /// Registry keeping objects in memory, counting requests.
+    #[derive(Clone, Default)]
+    struct FakeRegistry(Arc<Mutex<FakeState>>);
+
+    #[derive(Default)]
+    struct FakeState {
+        apps: HashMap<String, Application>,
+        devices: HashMap<String, Device>,
+        app_fetches: usize,
+        device_fetches: usize,
+        updates: usize,
+        // Updates to reject, as if someone else wrote the device first
+        conflicts: usize,
+    }
+
+    impl FakeRegistry {
+        fn state(&self) -> std::sync::MutexGuard<'_, FakeState> {
+            self.0.lock().unwrap()
+        }
+
+        /// Change a device like a user would, bumping its generation.
+        fn edit_device(&self, name: &str, edit: impl Fn(&mut Device)) {
+            let mut state = self.state();
+            let device = state.devices.get_mut(name).unwrap();
+            edit(device);
+            device.metadata.generation += 1;
+            bump(&mut device.metadata.resource_version);
+        }
+    }
+
+    fn bump(resource_version: &mut String) {
+        *resource_version = (resource_version.parse::<u32>().unwrap() + 1).to_string();
+    }
+
+    #[async_trait::async_trait]
+    impl Registry for FakeRegistry {
+        async fn get_app(&self, application: &str) -> Result<Option<Application>, ClientError> {
+            let mut state = self.state();
+            state.app_fetches += 1;
+            Ok(state.apps.get(application).cloned())
+        }
+
+        async fn get_device(&self, _: &str, device: &str) -> Result<Option<Device>, ClientError> {
+            let mut state = self.state();
+            state.device_fetches += 1;
+            Ok(state.devices.get(device).cloned())
+        }
+
+        async fn update_device(&self, device: &Device) -> Result<bool, ClientError> {
+            let mut state = self.state();
+            if state.conflicts > 0 {
+                state.conflicts -= 1;
+                let stored = state.devices.get_mut(&device.metadata.name).unwrap();
+                bump(&mut stored.metadata.resource_version);
+            }
+            let stored = state.devices.get_mut(&device.metadata.name).unwrap();
+            if stored.metadata.resource_version != device.metadata.resource_version {
+                return Err(ClientError::Service {
+                    code: StatusCode::CONFLICT,
+                    error: ErrorInformation {
+                        error: "Conflict".to_string(),
+                        message: String::new(),
+                    },
+                });
+            }
+            *stored = device.clone();
+            bump(&mut stored.metadata.resource_version);
+            state.updates += 1;
+            Ok(true)
+        }
+    }
+
+    fn app(firmware: &str) -> Application {
+        serde_json::from_value(json!({
+            "metadata": {"name": "app", "resourceVersion": "1"},
+            "spec": {"firmware": {"file": {"name": firmware}}},
+        }))
+        .unwrap()
+    }
+
+    fn device(name: &str) -> Device {
+        serde_json::from_value(json!({
+            "metadata": {"application": "app", "name": name, "resourceVersion": "1", "generation": 1},
+        }))
+        .unwrap()
+    }
+
+    fn new_index(registry: &FakeRegistry, ttl: Duration) -> Index<FakeRegistry> {
+        registry
+            .state()
+            .apps
+            .insert("app".to_string(), app("firmware-1"));
+        registry
+            .state()
+            .devices
+            .insert("dev".to_string(), device("dev"));
+        Index::new(
+            registry.clone(),
+            ttl,
+            10,
+            StatusPolicy {
+                progress_step: 10.0,
+                interval: Duration::from_secs(60),
+                conflict_retries: 2,
+            },
+        )
+    }
+
+    async fn firmware(index: &Index<FakeRegistry>) -> String {
+        let resources = index.resources("app", "dev").await.unwrap();
+        match resources.latest_version().unwrap() {
+            Some(FirmwareSpec::FILE { name, .. }) => name,
+            spec => panic!("Unexpected firmware {:?}", spec),
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_ttl() {
+        let registry = FakeRegistry::default();
+        let index = new_index(&registry, Duration::from_millis(200));
+        assert_eq!("firmware-1", firmware(&index).await);
+        assert_eq!("firmware-1", firmware(&index).await);
+        assert_eq!(1, registry.state().device_fetches);
+        assert_eq!(1, registry.state().app_fetches);
+
+        // Served from the cache until the entries expire
+        registry
+            .state()
+            .apps
+            .insert("app".to_string(), app("firmware-2"));
+        assert_eq!("firmware-1", firmware(&index).await);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!("firmware-2", firmware(&index).await);
+        assert_eq!(2, registry.state().app_fetches);
+
+        // Zero TTL disables caching
+        let registry = FakeRegistry::default();
+        let index = new_index(&registry, Duration::ZERO);
+        firmware(&index).await;
+        firmware(&index).await;
+        assert_eq!(2, registry.state().device_fetches);
+        assert_eq!(2, registry.state().app_fetches);
+    }
+
+    #[test]
+    fn cache_device_changes() {
+        let mut cache = Cache::new(Duration::from_secs(60), 10);
+        cache.put_application("app", Some(app("firmware-1")));
+        cache.put_device("app", "dev", Some(device("dev")));
+        assert!(cache.application("app").is_some());
+
+        // Refetched without changes
+        cache.put_device("app", "dev", Some(device("dev")));
+        assert!(cache.application("app").is_some());
+
+        // Refetched after our own status write, which keeps the generation
+        let mut written = device("dev");
+        written.metadata.resource_version = "2".to_string();
+        cache.put_written_device("app", "dev", written.clone());
+        written.metadata.resource_version = "3".to_string();
+        cache.put_device("app", "dev", Some(written.clone()));
+        assert!(cache.application("app").is_some());
+
+        // Changed by someone else
+        written.metadata.resource_version = "4".to_string();
+        cache.put_device("app", "dev", Some(written.clone()));
+        assert!(cache.application("app").is_none());
+
+        cache.put_application("app", Some(app("firmware-1")));
+        cache.put_written_device("app", "dev", written.clone());
+        written.metadata.generation += 1;
+        written.metadata.resource_version = "5".to_string();
+        cache.put_device("app", "dev", Some(written));
+        assert!(cache.application("app").is_none());
+
+        // Deleted
+        cache.put_application("app", Some(app("firmware-1")));
+        cache.put_device("app", "dev", None);
+        assert!(cache.application("app").is_none());
+        assert!(matches!(
+            cache.device("app", "dev", false),
+            Some((None, true))
+        ));
+    }
+
+    #[tokio::test]
+    async fn device_edit_refreshes_application() {
+        let registry = FakeRegistry::default();
+        let index = new_index(&registry, Duration::from_secs(60));
+        assert_eq!("firmware-1", firmware(&index).await);
+
+        // A status write conflicts with the edit, refetching the device and its application
+        registry
+            .state()
+            .apps
+            .insert("app".to_string(), app("firmware-2"));
+        registry.edit_device("dev", |d| {
+            d.metadata
+                .labels
+                .insert("rollout".to_string(), "2".to_string());
+        });
+        let status = Status::first(b"1", None, None);
+        index
+            .update_status("app", "dev", None, &status, Err("Failed".to_string()))
+            .await
+            .unwrap();
+        assert_eq!("firmware-2", firmware(&index).await);
+    }
 
-            //     .unwrap_or(Ok(Default::default()))?;
-            update_status(&mut s, status, data);
-            device.set_section::<FirmwareStatus>(s)?;
-            self.client.update_device(&device).await?;
+    fn progress_status(progress: Option<f32>) -> FirmwareStatus {
+        let mut status = FirmwareStatus {
+            current: "1.0.0".to_string(),
+            target: "2.0.0".to_string(),
+            ..Default::default()
+        };
+        status.conditions.update("InSync", false);
+        if let Some(progress) = progress {
+            status.conditions.update(
+                UPDATE_PROGRESS,
+                ConditionStatus {
+                    status: None,
+                    reason: None,
+                    message: Some(format!("{:.2}", progress)),
+                },
+            );
         }
-        Ok(())
+        status
+    }
+
+    #[test]
+    fn status_policy() {
+        let policy = StatusPolicy {
+            progress_step: 30.0,
+            interval: Duration::from_secs(60),
+            conflict_retries: 2,
+        };
+        let recently = Some(Instant::now());
+        let long_ago = Instant::now().checked_sub(Duration::from_secs(120));
+
+        // First write of a device
+        assert!(policy.needs_write(&FirmwareStatus::default(), &progress_status(None), None));
+        assert!(policy.needs_write(
+            &progress_status(None),
+            &progress_status(Some(0.0)),
+            recently
+        ));
+
+        // No change
+        assert!(!policy.needs_write(
+            &progress_status(Some(10.0)),
+            &progress_status(Some(10.0)),
+            long_ago
+        ));
+
+        // Step boundaries
+        assert!(!policy.needs_write(
+            &progress_status(Some(10.0)),
+            &progress_status(Some(29.99)),
+            recently
+        ));
+        assert!(policy.needs_write(
+            &progress_status(Some(29.99)),
+            &progress_status(Some(30.0)),
+            recently
+        ));
+        assert!(!policy.needs_write(
+            &progress_status(Some(30.0)),
+            &progress_status(Some(59.0)),
+            recently
+        ));
+
+        // Completed, although no step boundary is crossed
+        assert!(policy.needs_write(
+            &progress_status(Some(90.0)),
+            &progress_status(Some(100.0)),
+            recently
+        ));
+
+        // Interval expired, or nothing written yet
+        assert!(policy.needs_write(
+            &progress_status(Some(10.0)),
+            &progress_status(Some(11.0)),
+            long_ago
+        ));
+        assert!(policy.needs_write(
+            &progress_status(Some(10.0)),
+            &progress_status(Some(11.0)),
+            None
+        ));
+
+        // Condition and version changes
+        let mut synced = progress_status(None);
+        synced.conditions.update("InSync", true);
+        assert!(policy.needs_write(&progress_status(Some(50.0)), &synced, recently));
+        let mut retargeted = progress_status(Some(50.0));
+        retargeted.target = "3.0.0".to_string();
+        assert!(policy.needs_write(&progress_status(Some(50.0)), &retargeted, recently));
+    }
+
+    #[tokio::test]
+    async fn status_conflicts() {
+        let registry = FakeRegistry::default();
+        let index = new_index(&registry, Duration::from_secs(60));
+
+        // Retried with the current resourceVersion
+        let status = Status::first(b"1", None, None);
+        registry.state().conflicts = 2;
+        index
+            .update_status("app", "dev", None, &status, Err("Failed".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(1, registry.state().updates);
+        assert_eq!(3, registry.state().device_fetches);
+        let written: FirmwareStatus = registry.state().devices["dev"]
+            .section::<FirmwareStatus>()
+            .unwrap()
+            .unwrap();
+        assert!(written
+            .conditions
+            .iter()
+            .any(|c| c.reason.as_deref() == Some("Failed")));
+
+        // Gives up after the configured retries
+        registry.state().conflicts = 3;
+        assert!(index
+            .update_status("app", "dev", None, &status, Err("Other".to_string()))
+            .await
+            .is_err());
+        assert_eq!(1, registry.state().updates);
+
+        // Unchanged status is not written
+        registry.state().conflicts = 0;
+        index
+            .update_status("app", "dev", None, &status, Err("Other".to_string()))
+            .await
+            .unwrap();
+        index
+            .update_status("app", "dev", None, &status, Err("Other".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(2, registry.state().updates);
+    }
+
+    #[tokio::test]
+    async fn channels() {
+        let registry = FakeRegistry::default();
+        let index = new_index(&registry, Duration::from_secs(60));
+        let mut app = app("firmware-1");
+        app.set_section(FirmwareChannels(
+            [(
+                "beta".to_string(),
+                FirmwareSpec::FILE {
+                    name: "firmware-beta".to_string(),
+                    version: None,
+                    poll_interval: None,
+                },
+            )]
+            .into_iter()
+            .collect(),
+        ))
+        .unwrap();
+        registry.state().apps.insert("app".to_string(), app);
+
+        let resources = index.resources("app", "dev").await.unwrap();
+        assert!(matches!(
+            resources.channel("beta").unwrap(),
+            Some(FirmwareSpec::FILE { name, .. }) if name == "firmware-beta"
+        ));
+        assert!(resources.channel("stable").unwrap().is_none());
+        let resources = index.resources("other", "dev").await.unwrap();
+        assert!(resources.channel("beta").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn component_status() {
+        let registry = FakeRegistry::default();
+        let index = new_index(&registry, Duration::from_secs(60));
+        let all = vec!["modem".to_string(), "app".to_string()];
+        let metadata = |version: &str| Metadata {
+            version: version.as_bytes().to_vec(),
+            checksum: "sha256:00".to_string(),
+            size: 1024,
+            verification: None,
+        };
+        let status = || -> FirmwareStatus {
+            registry.state().devices["dev"]
+                .section::<FirmwareStatus>()
+                .unwrap()
+                .unwrap()
+        };
+        let modem = Component {
+            name: "modem",
+            all: &all,
+        };
+        let app = Component {
+            name: "app",
+            all: &all,
+        };
+
+        // Modem in sync, application still updating
+        index
+            .update_status(
+                "app",
+                "dev",
+                Some(&modem),
+                &Status::first(b"2.0.0", None, None),
+                Ok(&metadata("2.0.0")),
+            )
+            .await
+            .unwrap();
+        index
+            .update_status(
+                "app",
+                "dev",
+                Some(&app),
+                &Status::update(b"1.0.0", None, 512, b"2.0.0", None),
+                Ok(&metadata("2.0.0")),
+            )
+            .await
+            .unwrap();
+        assert!(in_sync(&status().components["modem"]));
+        assert!(!in_sync(&status().components["app"]));
+        assert_eq!(Some(50.0), progress(&status().components["app"]));
+        assert!(!in_sync(&status()));
+        let resources = index.resources("app", "dev").await.unwrap();
+        assert!(resources.components_in_sync(&all[..1]).unwrap());
+        assert!(!resources.components_in_sync(&all).unwrap());
+
+        // In sync once all components are
+        index
+            .update_status(
+                "app",
+                "dev",
+                Some(&app),
+                &Status::first(b"2.0.0", None, None),
+                Ok(&metadata("2.0.0")),
+            )
+            .await
+            .unwrap();
+        assert!(in_sync(&status()));
+        let resources = index.resources("app", "dev").await.unwrap();
+        assert!(resources.components_in_sync(&all).unwrap());
     }
 }
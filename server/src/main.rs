@@ -1,23 +1,39 @@
-use anyhow::{anyhow, Context};
+use ajour_schema::LabelSelector;
+use anyhow::Context;
 use clap::Parser;
 use std::path::PathBuf;
 
 use drogue_client::openid::AccessTokenProvider;
 use paho_mqtt as mqtt;
 
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::time::Duration;
 
+mod config;
+mod cosign;
+mod credentials;
+mod discovery;
 mod file;
 mod hawkbit;
 mod health;
+mod http;
 mod index;
 mod metadata;
 mod oci;
+mod s3;
 mod server;
+mod telemetry;
+mod transport;
 mod updater;
+mod validate;
 
 #[derive(Parser, Debug)]
 struct Args {
+    /// Configuration file (.toml, .yaml or .yml), command line arguments override its values
+    #[clap(long)]
+    config: Option<PathBuf>,
+
     /// Prefix to use for container registry storing images
     #[clap(long)]
     oci_registry_prefix: Option<String>,
@@ -38,23 +54,85 @@ struct Args {
     #[clap(long)]
     oci_registry_user: Option<String>,
 
+    /// File holding the token to use for authenticating to registry
+    #[clap(long)]
+    oci_registry_token_file: Option<PathBuf>,
+
+    /// Docker config file with registry credentials, such as a mounted pull secret
+    #[clap(long)]
+    oci_docker_config: Option<PathBuf>,
+
     /// Do not require registry to be valid cert and host
     #[clap(long)]
     oci_registry_insecure: bool,
 
-    // Max number of OCI firmware cache entries
-    #[clap(long, default_value_t = 50)]
-    oci_cache_entries_max: usize,
+    /// Max number of OCI firmware cache entries (default 50)
+    #[clap(long)]
+    oci_cache_entries_max: Option<usize>,
 
     #[clap(long)]
     oci_cache_expiry: Option<u64>,
 
+    /// Public key (PEM) accepted for cosign image signatures, may be repeated
+    #[clap(long, multiple_occurrences = true)]
+    oci_public_key: Vec<PathBuf>,
+
+    /// Whether container images must have a valid cosign signature
+    #[clap(long, arg_enum)]
+    oci_signature_policy: Option<cosign::SignaturePolicy>,
+
     #[clap(long)]
     file_registry_enable: bool,
 
     #[clap(long)]
     file_registry_path: Option<PathBuf>,
 
+    /// Derive firmware metadata from the binary when no metadata file exists
+    #[clap(long)]
+    file_registry_auto_metadata: bool,
+
+    #[clap(long)]
+    http_registry_enable: bool,
+
+    /// URL prefix that the HTTP store may fetch firmware from, may be repeated
+    #[clap(long, multiple_occurrences = true)]
+    http_url: Vec<String>,
+
+    #[clap(long)]
+    http_secrets_path: Option<PathBuf>,
+
+    /// URL prefix that secrets of the HTTP store may be sent to, may be repeated
+    #[clap(long, multiple_occurrences = true)]
+    http_secret_url: Vec<String>,
+
+    /// Enable firmware stored in an S3 bucket
+    #[clap(long)]
+    s3_enable: bool,
+
+    /// Bucket holding firmware
+    #[clap(long)]
+    s3_bucket: Option<String>,
+
+    /// Prefix of firmware objects within the bucket
+    #[clap(long)]
+    s3_prefix: Option<String>,
+
+    /// Bucket region
+    #[clap(long)]
+    s3_region: Option<String>,
+
+    /// Endpoint of an S3 compatible store such as MinIO
+    #[clap(long)]
+    s3_endpoint: Option<String>,
+
+    /// Access key, read from AWS_ACCESS_KEY_ID if not set
+    #[clap(long)]
+    s3_access_key: Option<String>,
+
+    /// Secret key, read from AWS_SECRET_ACCESS_KEY if not set
+    #[clap(long)]
+    s3_secret_key: Option<String>,
+
     #[clap(long)]
     hawkbit_enable: bool,
 
@@ -67,18 +145,45 @@ struct Args {
     #[clap(long)]
     hawkbit_gateway_token: Option<String>,
 
+    /// Directory of secrets with Hawkbit gateway or target tokens
+    #[clap(long)]
+    hawkbit_secrets_path: Option<PathBuf>,
+
+    /// URL of another Hawkbit instance firmware specs may point to, may be repeated
+    #[clap(long, multiple_occurrences = true)]
+    hawkbit_instance: Vec<String>,
+
     /// Mqtt server uri (tcp://host:port)
     #[clap(long)]
-    mqtt_uri: String,
+    mqtt_uri: Option<String>,
 
     /// Mqtt group id for shared subscription (for horizontal scaling)
     #[clap(long)]
     mqtt_group_id: Option<String>,
 
     /// Device registry URL
-    /// Mqtt server uri (tcp://host:port)
     #[clap(long)]
-    device_registry: String,
+    device_registry: Option<String>,
+
+    /// Time in seconds to cache device registry objects, 0 to disable (default 30)
+    #[clap(long)]
+    registry_cache_ttl: Option<u64>,
+
+    /// Max number of cached device registry objects (default 1000)
+    #[clap(long)]
+    registry_cache_entries_max: Option<usize>,
+
+    /// Write firmware update progress when crossing a multiple of this many percent (default 10)
+    #[clap(long)]
+    status_progress_step: Option<f32>,
+
+    /// Write firmware update progress at least this often, in seconds (default 30)
+    #[clap(long)]
+    status_progress_interval: Option<u64>,
+
+    /// Seconds between device check-ins, unless set for the application or device
+    #[clap(long)]
+    poll_interval: Option<u32>,
 
     /// Name of specific application to manage firmware updates for (will use all accessible from service account by default)
     #[clap(long)]
@@ -88,13 +193,21 @@ struct Args {
     #[clap(long)]
     exclude_applications: Option<String>,
 
+    /// Only manage applications matching this label selector (e.g. "ajour=enabled,tier!=test")
+    #[clap(long)]
+    application_selector: Option<LabelSelector>,
+
+    /// Interval in seconds between checking for new or removed applications, 0 to disable (default 60)
+    #[clap(long)]
+    discovery_interval: Option<u64>,
+
     /// Token for authenticating ajour to Drogue IoT
     #[clap(long)]
-    token: String,
+    token: Option<String>,
 
     /// User for authenticating ajour to Drogue IoT
     #[clap(long)]
-    user: String,
+    user: Option<String>,
 
     /// Path to CA
     #[clap(long)]
@@ -112,9 +225,165 @@ struct Args {
     #[clap(long)]
     disable_health: bool,
 
-    /// Port for health endpoint
-    #[clap(long, default_value_t = 8080)]
-    health_port: u16,
+    /// Port for health endpoint (default 8080)
+    #[clap(long)]
+    health_port: Option<u16>,
+
+    /// Exporter to use for OpenTelemetry traces
+    #[clap(long, arg_enum)]
+    tracing_exporter: Option<telemetry::TracingExporter>,
+
+    /// OTLP collector endpoint (http://host:port) when using the otlp tracing exporter
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+}
+
+impl Args {
+    /// Apply command line arguments on top of the configuration file and environment.
+    fn apply(self, config: &mut config::Config) {
+        if self.oci_registry_enable {
+            config
+                .stores
+                .oci
+                .entry(config::DEFAULT_STORE.to_string())
+                .or_default();
+        }
+        if let Some(store) = config.stores.oci.get_mut(config::DEFAULT_STORE) {
+            override_with(&mut store.prefix, self.oci_registry_prefix);
+            store.tls |= self.oci_registry_tls;
+            override_with(&mut store.user, self.oci_registry_user);
+            override_with(&mut store.token, self.oci_registry_token);
+            override_with(&mut store.token_file, self.oci_registry_token_file);
+            override_with(&mut store.docker_config, self.oci_docker_config);
+            store.insecure |= self.oci_registry_insecure;
+            if let Some(max) = self.oci_cache_entries_max {
+                store.cache_entries_max = max;
+            }
+            override_with(&mut store.cache_expiry, self.oci_cache_expiry);
+            if !self.oci_public_key.is_empty() {
+                store.public_keys = self.oci_public_key;
+            }
+            if let Some(policy) = self.oci_signature_policy {
+                store.signature_policy = policy;
+            }
+        }
+
+        if self.hawkbit_enable {
+            config
+                .stores
+                .hawkbit
+                .entry(config::DEFAULT_STORE.to_string())
+                .or_default();
+        }
+        if let Some(store) = config.stores.hawkbit.get_mut(config::DEFAULT_STORE) {
+            override_with(&mut store.url, self.hawkbit_url);
+            override_with(&mut store.tenant, self.hawkbit_tenant);
+            override_with(&mut store.gateway_token, self.hawkbit_gateway_token);
+            override_with(&mut store.secrets_path, self.hawkbit_secrets_path);
+            if !self.hawkbit_instance.is_empty() {
+                store.instances = self.hawkbit_instance;
+            }
+        }
+
+        if self.file_registry_enable {
+            config
+                .stores
+                .file
+                .entry(config::DEFAULT_STORE.to_string())
+                .or_default();
+        }
+        if let Some(store) = config.stores.file.get_mut(config::DEFAULT_STORE) {
+            override_with(&mut store.path, self.file_registry_path);
+            store.auto_metadata |= self.file_registry_auto_metadata;
+        }
+
+        if self.http_registry_enable {
+            config
+                .stores
+                .http
+                .entry(config::DEFAULT_STORE.to_string())
+                .or_default();
+        }
+        if let Some(store) = config.stores.http.get_mut(config::DEFAULT_STORE) {
+            if !self.http_url.is_empty() {
+                store.urls = self.http_url;
+            }
+            override_with(&mut store.secrets_path, self.http_secrets_path);
+            if !self.http_secret_url.is_empty() {
+                store.secret_urls = self.http_secret_url;
+            }
+        }
+
+        if self.s3_enable {
+            config
+                .stores
+                .s3
+                .entry(config::DEFAULT_STORE.to_string())
+                .or_default();
+        }
+        if let Some(store) = config.stores.s3.get_mut(config::DEFAULT_STORE) {
+            override_with(&mut store.bucket, self.s3_bucket);
+            override_with(&mut store.endpoint, self.s3_endpoint);
+            override_with(&mut store.access_key, self.s3_access_key);
+            override_with(&mut store.secret_key, self.s3_secret_key);
+            if let Some(prefix) = self.s3_prefix {
+                store.prefix = prefix;
+            }
+            if let Some(region) = self.s3_region {
+                store.region = region;
+            }
+        }
+
+        override_with(&mut config.mqtt.uri, self.mqtt_uri);
+        override_with(&mut config.mqtt.group_id, self.mqtt_group_id);
+        override_with(&mut config.mqtt.ca_path, self.ca_path);
+        config.mqtt.disable_tls |= self.disable_tls;
+        config.mqtt.insecure_tls |= self.insecure_tls;
+
+        override_with(&mut config.registry.url, self.device_registry);
+        override_with(&mut config.registry.user, self.user);
+        override_with(&mut config.registry.token, self.token);
+        if let Some(ttl) = self.registry_cache_ttl {
+            config.registry.cache_ttl = ttl;
+        }
+        if let Some(max) = self.registry_cache_entries_max {
+            config.registry.cache_entries_max = max;
+        }
+        if let Some(step) = self.status_progress_step {
+            config.status.progress_step = step;
+        }
+        if let Some(interval) = self.status_progress_interval {
+            config.status.progress_interval = interval;
+        }
+        override_with(&mut config.polling.interval, self.poll_interval);
+
+        if let Some(app) = self.application {
+            config.applications.include = vec![app];
+        }
+        if let Some(excluded) = self.exclude_applications {
+            config.applications.exclude = excluded.split(",").map(|s| s.to_string()).collect();
+        }
+        override_with(&mut config.applications.selector, self.application_selector);
+        if let Some(interval) = self.discovery_interval {
+            config.applications.discovery_interval = interval;
+        }
+
+        config.health.disable |= self.disable_health;
+        if let Some(port) = self.health_port {
+            config.health.port = port;
+        }
+
+        if let Some(exporter) = self.tracing_exporter {
+            config.tracing.exporter = exporter;
+        }
+        override_with(&mut config.tracing.otlp_endpoint, self.otlp_endpoint);
+    }
+}
+
+fn override_with<T>(value: &mut Option<T>, arg: Option<T>) {
+    if arg.is_some() {
+        *value = arg;
+    }
 }
 
 #[tokio::main]
@@ -122,50 +391,100 @@ async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     env_logger::init();
 
-    let oci_client = if args.oci_registry_enable {
-        log::info!("Enabling Container Registry");
-        Some(oci::OciClient::new(
+    let mut config: config::Config = ajour_schema::config::load(args.config.as_deref(), "AJOUR")?;
+    args.apply(&mut config);
+    config.validate()?;
+
+    telemetry::init(
+        config.tracing.exporter,
+        config.tracing.otlp_endpoint.clone(),
+    )?;
+
+    let mut stores = updater::Stores::default();
+    for (name, store) in config.stores.oci {
+        log::info!("Enabling Container Registry '{}'", name);
+        let client = oci::OciClient::new(
             oci::ClientConfig {
                 platform_resolver: None,
-                protocol: if args.oci_registry_tls {
+                protocol: if store.tls {
                     oci::ClientProtocol::Https
                 } else {
                     oci::ClientProtocol::Http
                 },
-                accept_invalid_hostnames: args.oci_registry_insecure,
-                accept_invalid_certificates: args.oci_registry_insecure,
+                accept_invalid_hostnames: store.insecure,
+                accept_invalid_certificates: store.insecure,
                 extra_root_certificates: Vec::new(),
             },
-            args.oci_registry_prefix.unwrap().clone(),
-            args.oci_registry_user.clone(),
-            args.oci_registry_token.clone(),
-            args.oci_cache_entries_max,
-            args.oci_cache_expiry.map(|s| Duration::from_secs(s)),
-        ))
-    } else {
-        None
-    };
+            store.prefix.clone().unwrap_or_default(),
+            credentials::RegistryCredentials::new(
+                store.prefix.as_deref().unwrap_or_default(),
+                store.user,
+                store.token,
+                store.token_file,
+                store.docker_config,
+            ),
+            Duration::from_secs(store.token_refresh),
+            store.cache_entries_max,
+            store.cache_expiry.map(Duration::from_secs),
+            cosign::Verifier::new(&store.public_keys, store.cache_entries_max)?,
+            store.signature_policy,
+            store.platform_label,
+        );
+        stores.oci.insert(name, client);
+    }
 
-    let hawkbit_client = if args.hawkbit_enable {
-        log::info!("Enabling Hawkbit Registry");
-        Some(hawkbit::HawkbitClient::new(
-            &args.hawkbit_url.unwrap(),
-            &args.hawkbit_tenant.unwrap(),
-            &args.hawkbit_gateway_token.unwrap(),
-        ))
-    } else {
-        None
-    };
+    for (name, store) in config.stores.hawkbit {
+        log::info!("Enabling Hawkbit Registry '{}'", name);
+        let client = hawkbit::HawkbitClient::new(
+            &store.url.unwrap_or_default(),
+            &store.tenant.unwrap_or_default(),
+            store.gateway_token,
+            store.secrets_path,
+            store.instances,
+            config.retry.devices_max,
+        );
+        stores.hawkbit.insert(name, client);
+    }
 
-    let file_client = if args.file_registry_enable {
-        log::info!("Enabling File Registry");
-        Some(file::FileClient::new(&args.file_registry_path.unwrap()))
-    } else {
-        None
-    };
+    for (name, store) in config.stores.file {
+        log::info!("Enabling File Registry '{}'", name);
+        let client = file::FileClient::new(
+            &store.path.unwrap_or_default(),
+            store.auto_metadata,
+            store.cache_entries_max,
+        );
+        stores.file.insert(name, client);
+    }
 
-    let mqtt_uri = args.mqtt_uri;
-    let token = args.token;
+    for (name, store) in config.stores.http {
+        log::info!("Enabling HTTP Registry '{}'", name);
+        let client = http::HttpClient::new(
+            store.urls,
+            store.secrets_path,
+            store.secret_urls,
+            store.cache_entries_max,
+        )?;
+        stores.http.insert(name, client);
+    }
+
+    for (name, store) in config.stores.s3 {
+        log::info!("Enabling S3 Registry '{}'", name);
+        let client = s3::S3Client::new(
+            &store.bucket.unwrap_or_default(),
+            store.prefix,
+            &store.region,
+            store.endpoint,
+            store.access_key,
+            store.secret_key,
+            store.range_size,
+            store.cache_entries_max,
+        )?;
+        stores.s3.insert(name, client);
+    }
+
+    let mqtt_uri = config.mqtt.uri.unwrap_or_default();
+    let user = config.registry.user.unwrap_or_default();
+    let token = config.registry.token.unwrap_or_default();
 
     let mqtt_opts = mqtt::CreateOptionsBuilder::new()
         .server_uri(mqtt_uri)
@@ -175,24 +494,25 @@ async fn main() -> anyhow::Result<()> {
     let mut mqtt_client = mqtt::AsyncClient::new(mqtt_opts)?;
 
     let tp = AccessTokenProvider {
-        user: args.user.to_string(),
+        user: user.to_string(),
         token: token.to_string(),
     };
 
-    let url = reqwest::Url::parse(&args.device_registry)?;
+    let url = reqwest::Url::parse(&config.registry.url.unwrap_or_default())?;
     let drg = index::DrogueClient::new(reqwest::Client::new(), url, tp);
 
     let mut conn_opts = mqtt::ConnectOptionsBuilder::new();
-    conn_opts.user_name(args.user);
+    conn_opts.user_name(user);
     conn_opts.password(token.clone());
     conn_opts.keep_alive_interval(Duration::from_secs(30));
     conn_opts.automatic_reconnect(Duration::from_millis(100), Duration::from_secs(5));
 
-    if !args.disable_tls {
-        let ca = args
+    if !config.mqtt.disable_tls {
+        let ca = config
+            .mqtt
             .ca_path
             .unwrap_or("/etc/ssl/certs/ca-bundle.crt".to_string());
-        let ssl_opts = if args.insecure_tls {
+        let ssl_opts = if config.mqtt.insecure_tls {
             mqtt::SslOptionsBuilder::new()
                 .trust_store(&ca)?
                 .enable_server_cert_auth(false)
@@ -229,48 +549,54 @@ async fn main() -> anyhow::Result<()> {
         .await
         .context("Failed to connect to MQTT endpoint")?;
 
-    let healthz = if !args.disable_health {
-        Some(health::HealthServer::new(args.health_port))
+    let ready = Arc::new(AtomicBool::new(false));
+    let healthz = if !config.health.disable {
+        Some(health::HealthServer::new(config.health.port, ready.clone()))
     } else {
         None
     };
 
-    let excluded: Vec<String> = if let Some(excluded) = args.exclude_applications {
-        excluded.split(",").map(|s| s.to_string()).collect()
-    } else {
-        Vec::new()
-    };
-    let mut applications = Vec::new();
-    if let Some(app) = args.application {
-        applications.push(app);
-    } else {
-        let apps: Option<Vec<drogue_client::registry::v1::Application>> =
-            drg.list_apps(None).await?;
-        if let Some(apps) = apps {
-            for app in apps {
-                if !excluded.contains(&app.metadata.name) {
-                    applications.push(app.metadata.name);
-                }
-            }
-        } else {
-            return Err(anyhow!("no applications available"));
-        }
-    }
-
-    log::info!(
-        "Starting server subscribing to applications: {:?}",
-        applications
+    let discovery = discovery::Discovery::new(
+        drg.clone(),
+        config.applications.include,
+        config.applications.exclude,
+        config.applications.selector,
+        Some(Duration::from_secs(config.applications.discovery_interval)).filter(|i| !i.is_zero()),
     );
 
-    let index = index::Index::new(drg);
-    let updater = updater::Updater::new(index, oci_client, hawkbit_client, file_client);
+    let index = index::Index::new(
+        drg,
+        Duration::from_secs(config.registry.cache_ttl),
+        config.registry.cache_entries_max,
+        index::StatusPolicy {
+            progress_step: config.status.progress_step,
+            interval: Duration::from_secs(config.status.progress_interval),
+            conflict_retries: config.status.conflict_retries,
+        },
+    );
+    let updater = updater::Updater::new(
+        index,
+        stores,
+        config.applications.overrides,
+        updater::RetryPolicy {
+            initial_backoff: Duration::from_secs(config.retry.initial_backoff),
+            max_backoff: Duration::from_secs(config.retry.max_backoff),
+            budget: config.retry.budget,
+            devices_max: config.retry.devices_max,
+        },
+        updater::PollPolicy {
+            interval: config.polling.interval,
+            jitter: config.polling.jitter,
+        },
+    );
 
-    let mut app = server::Server::new(mqtt_client, args.mqtt_group_id, applications, updater);
+    let mut app = server::Server::new(mqtt_client, config.mqtt.group_id, discovery, updater, ready);
 
-    if let Some(mut h) = healthz {
-        futures::try_join!(app.run(), h.run())?;
+    let result = if let Some(mut h) = healthz {
+        futures::try_join!(app.run(), h.run()).map(|_| ())
     } else {
-        app.run().await?;
-    }
-    Ok(())
+        app.run().await
+    };
+    telemetry::shutdown();
+    result
 }
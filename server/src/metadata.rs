@@ -1,4 +1,6 @@
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Metadata {
@@ -6,4 +8,32 @@ pub struct Metadata {
     pub version: Vec<u8>,
     pub checksum: String,
     pub size: u32,
+    /// Result of verifying the firmware signature, if verified
+    #[serde(skip)]
+    pub verification: Option<Verification>,
+}
+
+impl Metadata {
+    /// Check firmware against the SHA-256 checksum, hex encoded with an optional `sha256:` prefix.
+    pub fn verify_checksum(&self, data: &[u8]) -> Result<(), anyhow::Error> {
+        let expected = self.checksum.trim_start_matches("sha256:");
+        let actual = hex::encode(Sha256::digest(data));
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(anyhow!(
+                "Firmware checksum {} does not match the metadata checksum {}",
+                actual,
+                expected
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verification {
+    Verified,
+    /// Verification failed, but the firmware may be served
+    Failed(String),
+    /// Verification failed, and the firmware must not be served
+    Rejected(String),
 }
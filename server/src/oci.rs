@@ -1,108 +1,261 @@
+use crate::cosign::{SignaturePolicy, Verifier};
+use crate::credentials::RegistryCredentials;
 use crate::metadata::Metadata;
 use crate::updater::FirmwareStore;
 use ajour_schema::*;
 use anyhow::anyhow;
 pub use client::{ClientConfig, ClientProtocol};
 use lru::LruCache;
-use oci_distribution::{client, secrets::RegistryAuth, Reference};
+use oci_distribution::{
+    client,
+    manifest::{ImageIndexEntry, OciImageIndex, OciImageManifest, OciManifest},
+    secrets::RegistryAuth,
+    Reference, RegistryOperation,
+};
+use std::collections::HashMap;
 use tokio::time::{Duration, Instant};
 
+/// Annotations and config labels holding the firmware version, in order of precedence.
+const VERSION_KEYS: [&str; 2] = [
+    "io.drogue.ajour.version",
+    "org.opencontainers.image.version",
+];
+
+/// Annotation of an image index entry naming the platform, as an alternative to its platform.
+const PLATFORM_ANNOTATION: &str = "io.drogue.ajour.platform";
+
 pub struct OciClient {
     prefix: String,
-    auth: RegistryAuth,
+    credentials: RegistryCredentials,
     client: client::Client,
 
-    // Cache of metadata
-    metadata_cache: LruCache<String, (Instant, Metadata)>,
+    // Time of the last authentication by repository, to refresh bearer tokens before they expire
+    authenticated: HashMap<String, Instant>,
+    token_refresh: Duration,
+
+    // Cache of metadata, along with the manifest digest
+    metadata_cache: LruCache<String, (Instant, Metadata, String)>,
     metadata_cache_expiry: Option<Duration>,
 
     // Cached by checksum
     firmware_cache: LruCache<String, Vec<u8>>,
+
+    verifier: Verifier,
+    signature_policy: SignaturePolicy,
+    platform_label: String,
+}
+
+/// Image to fetch firmware from, and how to fetch it.
+pub struct OciParams {
+    pub image: String,
+    /// Registry prefix, overriding the prefix of the store
+    pub registry: Option<String>,
+    pub image_pull_policy: ImagePullPolicy,
+    /// Signature policy, uses the policy of the store if not set
+    pub signature_policy: Option<SignaturePolicy>,
+    /// Platform of the device, selecting the manifest of an image index
+    pub platform: Option<String>,
 }
 
 impl OciClient {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: ClientConfig,
         prefix: String,
-        user: Option<String>,
-        token: Option<String>,
+        credentials: RegistryCredentials,
+        token_refresh: Duration,
         cache_size: usize,
         metadata_cache_expiry: Option<Duration>,
+        verifier: Verifier,
+        signature_policy: SignaturePolicy,
+        platform_label: String,
     ) -> Self {
         Self {
             client: client::Client::new(config),
             prefix,
-            auth: token
-                .map(|t| RegistryAuth::Basic(user.unwrap_or("".to_string()), t))
-                .unwrap_or(RegistryAuth::Anonymous),
+            credentials,
+            authenticated: HashMap::new(),
+            token_refresh,
             metadata_cache: LruCache::new(cache_size),
             firmware_cache: LruCache::new(cache_size),
             metadata_cache_expiry,
+            verifier,
+            signature_policy,
+            platform_label,
         }
     }
 
+    /// Device label holding the platform of the device.
+    pub fn platform_label(&self) -> &str {
+        &self.platform_label
+    }
+
+    fn reference(&self, params: &OciParams) -> String {
+        let prefix = params.registry.as_ref().unwrap_or(&self.prefix);
+        format!("{}{}", prefix, params.image)
+    }
+
+    /// Credentials for the repository of an image, authenticating again if the token may expire.
+    async fn authenticate(&mut self, imageref: &Reference) -> Result<RegistryAuth, anyhow::Error> {
+        let auth = self.credentials.auth(imageref.registry())?;
+        let repository = format!("{}/{}", imageref.registry(), imageref.repository());
+        let expired = self
+            .authenticated
+            .get(&repository)
+            .map(|t| t.elapsed() >= self.token_refresh)
+            .unwrap_or(true);
+        if expired {
+            log::debug!("Authenticating to {}", repository);
+            self.client
+                .auth(imageref, &auth, RegistryOperation::Pull)
+                .await?;
+            self.authenticated.insert(repository, Instant::now());
+        }
+        Ok(auth)
+    }
+
+    /// Fetch metadata of an image, verifying its signature unless the policy is off.
     pub async fn fetch_metadata(
+        &mut self,
+        params: &OciParams,
+    ) -> Result<Option<Metadata>, anyhow::Error> {
+        let image = self.reference(params);
+        let imageref: Reference = image.parse()?;
+        let auth = self.authenticate(&imageref).await?;
+        let (mut metadata, digest) = self
+            .fetch_manifest(
+                &image,
+                &auth,
+                params.image_pull_policy,
+                params.platform.as_deref(),
+            )
+            .await?;
+        metadata.verification = self
+            .verifier
+            .verify(
+                &mut self.client,
+                &auth,
+                &imageref,
+                &digest,
+                params.signature_policy.unwrap_or(self.signature_policy),
+            )
+            .await;
+        Ok(Some(metadata))
+    }
+
+    async fn fetch_manifest(
         &mut self,
         image: &str,
+        auth: &RegistryAuth,
         image_pull_policy: ImagePullPolicy,
-    ) -> Result<Option<Metadata>, anyhow::Error> {
+        platform: Option<&str>,
+    ) -> Result<(Metadata, String), anyhow::Error> {
+        let key = format!("{}#{}", image, platform.unwrap_or(""));
         if let ImagePullPolicy::IfNotPresent = image_pull_policy {
             // Attempt cache lookup
-            if let Some((inserted, entry)) = self.metadata_cache.get(image) {
+            if let Some((inserted, entry, digest)) = self.metadata_cache.get(&key) {
                 // Discard outdated items, let the LRU logic clean them out eventually
                 if let Some(expiry) = self.metadata_cache_expiry {
                     let oldest = Instant::now() - expiry;
                     if inserted > &oldest {
                         log::debug!("Found metadata cache entry for {}", image);
-                        return Ok(Some(entry.clone()));
+                        return Ok((entry.clone(), digest.clone()));
                     } else {
                         log::debug!("Found expired entry for {}, fetching new", image);
                     }
                 } else {
                     log::debug!("Found metadata cache entry for {}", image);
-                    return Ok(Some(entry.clone()));
+                    return Ok((entry.clone(), digest.clone()));
                 }
             }
         }
-        let imageref = format!("{}{}", self.prefix, image).parse()?;
-        let manifest = self.client.pull_image_manifest(&imageref, &self.auth).await;
-        match manifest {
-            Ok((manifest, _)) => {
+        let imageref = image.parse()?;
+        match self.pull_manifest(&imageref, auth, platform).await {
+            Ok((manifest, digest, config)) => {
+                let version = version(&imageref, &manifest, &digest, &config);
                 for layer in manifest.layers.iter() {
                     if layer.media_type == "application/octet-stream" {
                         let metadata: Metadata = Metadata {
-                            version: imageref.tag().unwrap_or("").as_bytes().to_vec(),
+                            version: version.as_bytes().to_vec(),
                             checksum: layer.digest.clone(),
                             size: layer.size as u32,
+                            verification: None,
                         };
                         self.metadata_cache
-                            .put(image.to_string(), (Instant::now(), metadata.clone()));
-                        return Ok(Some(metadata));
+                            .put(key, (Instant::now(), metadata.clone(), digest.clone()));
+                        return Ok((metadata, digest));
                     }
                 }
                 Err(anyhow!("Unable to locate metadata in image config"))
             }
             Err(e) => {
                 log::info!("Error pulling manifest: {:?}", e);
-                Err(e.into())
+                Err(e)
             }
         }
     }
 
+    /// Pull the image manifest and config, selecting the manifest for the platform of an index.
+    ///
+    /// Returns the digest of the referenced manifest or index, which is the digest being signed.
+    async fn pull_manifest(
+        &mut self,
+        imageref: &Reference,
+        auth: &RegistryAuth,
+        platform: Option<&str>,
+    ) -> Result<(OciImageManifest, String, String), anyhow::Error> {
+        let (manifest, digest) = self.client.pull_manifest(imageref, auth).await?;
+        let (manifest, manifest_ref) = match manifest {
+            OciManifest::Image(manifest) => (manifest, imageref.clone()),
+            OciManifest::ImageIndex(index) => {
+                let entry = select_platform(&index, platform).ok_or_else(|| {
+                    anyhow!(
+                        "Image index {} has no manifest for platform {}",
+                        imageref,
+                        platform.unwrap_or("(none)")
+                    )
+                })?;
+                log::debug!("Selected manifest {} of {}", entry.digest, imageref);
+                let manifest_ref = Reference::with_digest(
+                    imageref.registry().to_string(),
+                    imageref.repository().to_string(),
+                    entry.digest.clone(),
+                );
+                match self.client.pull_manifest(&manifest_ref, auth).await? {
+                    (OciManifest::Image(manifest), _) => (manifest, manifest_ref),
+                    (OciManifest::ImageIndex(_), _) => {
+                        return Err(anyhow!("Nested image index in {}", imageref))
+                    }
+                }
+            }
+        };
+
+        let mut config = Vec::new();
+        self.client
+            .pull_blob(&manifest_ref, &manifest.config.digest, &mut config)
+            .await?;
+        Ok((
+            manifest,
+            digest,
+            String::from_utf8_lossy(&config).to_string(),
+        ))
+    }
+
     pub async fn fetch_firmware(
         &mut self,
-        image: &str,
+        params: &OciParams,
         metadata: &Metadata,
-        image_pull_policy: ImagePullPolicy,
     ) -> Result<Vec<u8>, anyhow::Error> {
-        if let ImagePullPolicy::IfNotPresent = image_pull_policy {
+        let image = self.reference(params);
+        if let ImagePullPolicy::IfNotPresent = params.image_pull_policy {
             if let Some(firmware) = self.firmware_cache.get(&metadata.checksum) {
                 log::debug!("Found firmware cache entry for {}", image);
                 return Ok(firmware.clone());
             }
         }
 
-        let imageref: Reference = format!("{}{}", self.prefix, image).parse()?;
+        let imageref: Reference = image.parse()?;
+        self.authenticate(&imageref).await?;
         let mut payload = Vec::new();
         let manifest = self
             .client
@@ -112,21 +265,95 @@ impl OciClient {
             Ok(()) => {
                 self.firmware_cache
                     .put(metadata.checksum.clone(), payload.clone());
-                return Ok(payload);
+                Ok(payload)
             }
             Err(e) => Err(e.into()),
         }
     }
 }
 
+/// Select the manifest of an image index matching the platform.
+///
+/// The platform is matched against the `io.drogue.ajour.platform` annotation of the entries, or
+/// their `os/architecture[/variant]` platform. Without a platform, an index with a single manifest
+/// is accepted.
+fn select_platform<'i>(
+    index: &'i OciImageIndex,
+    platform: Option<&str>,
+) -> Option<&'i ImageIndexEntry> {
+    match platform {
+        Some(platform) => index.manifests.iter().find(|entry| {
+            let annotated = entry
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get(PLATFORM_ANNOTATION))
+                .map(|p| p == platform)
+                .unwrap_or(false);
+            annotated
+                || entry
+                    .platform
+                    .as_ref()
+                    .map(|p| {
+                        let mut name = format!("{}/{}", p.os, p.architecture);
+                        if let Some(variant) = &p.variant {
+                            name = format!("{}/{}", name, variant);
+                        }
+                        name == platform
+                    })
+                    .unwrap_or(false)
+        }),
+        None if index.manifests.len() == 1 => index.manifests.first(),
+        None => None,
+    }
+}
+
+/// Firmware version of an image.
+///
+/// The version is read from the manifest annotations, then the labels of the image config. Images
+/// without a version fall back to the tag, or the start of the manifest digest if pulled by digest.
+fn version(
+    imageref: &Reference,
+    manifest: &OciImageManifest,
+    digest: &str,
+    config: &str,
+) -> String {
+    if let Some(annotations) = &manifest.annotations {
+        for key in VERSION_KEYS.iter() {
+            if let Some(version) = annotations.get(*key) {
+                return version.clone();
+            }
+        }
+    }
+
+    match serde_json::from_str::<serde_json::Value>(config) {
+        Ok(config) => {
+            for key in VERSION_KEYS.iter() {
+                if let Some(version) = config["config"]["Labels"][*key].as_str() {
+                    return version.to_string();
+                }
+            }
+        }
+        Err(e) => log::debug!("Unable to parse image config of {}: {:?}", imageref, e),
+    }
+
+    match imageref.tag() {
+        Some(tag) => tag.to_string(),
+        None => digest
+            .trim_start_matches("sha256:")
+            .chars()
+            .take(12)
+            .collect(),
+    }
+}
+
 #[async_trait::async_trait]
 impl FirmwareStore for OciClient {
-    type Params = (String, ImagePullPolicy);
+    type Params = OciParams;
     async fn fetch_metadata(
         &mut self,
         params: &Self::Params,
     ) -> Result<(Self::Context, Option<Metadata>), anyhow::Error> {
-        let m = OciClient::fetch_metadata(self, &params.0, params.1).await?;
+        let m = OciClient::fetch_metadata(self, params).await?;
         Ok(((), m))
     }
 
@@ -136,6 +363,7 @@ impl FirmwareStore for OciClient {
         _: &Self::Context,
         _: u32,
         _: u32,
+        _: &str,
     ) -> Result<(), anyhow::Error> {
         Ok(())
     }
@@ -147,7 +375,7 @@ impl FirmwareStore for OciClient {
         _: &Self::Context,
         metadata: &Metadata,
     ) -> Result<Vec<u8>, anyhow::Error> {
-        OciClient::fetch_firmware(self, &params.0, metadata, params.1).await
+        OciClient::fetch_firmware(self, params, metadata).await
     }
 
     async fn mark_synced(
@@ -158,4 +386,86 @@ impl FirmwareStore for OciClient {
     ) -> Result<(), anyhow::Error> {
         Ok(())
     }
+
+    async fn mark_failed(
+        &mut self,
+        _: &Self::Params,
+        _: &Self::Context,
+        _: &str,
+    ) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const DIGEST: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    fn manifest(annotations: serde_json::Value) -> OciImageManifest {
+        serde_json::from_value(json!({
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": DIGEST,
+                "size": 2
+            },
+            "layers": [],
+            "annotations": annotations
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn version_precedence() {
+        let tagged: Reference = "quay.io/org/firmware:latest".parse().unwrap();
+        let pinned: Reference = format!("quay.io/org/firmware@{}", DIGEST).parse().unwrap();
+        let config = json!({
+            "config": { "Labels": { "org.opencontainers.image.version": "1.0.0" } }
+        })
+        .to_string();
+
+        let annotated = manifest(json!({
+            "org.opencontainers.image.version": "1.1.0",
+            "io.drogue.ajour.version": "1.2.0"
+        }));
+        assert_eq!("1.2.0", version(&tagged, &annotated, DIGEST, &config));
+
+        let unannotated = manifest(json!({}));
+        assert_eq!("1.0.0", version(&pinned, &unannotated, DIGEST, &config));
+        assert_eq!("latest", version(&tagged, &unannotated, DIGEST, "{}"));
+        assert_eq!("6c3c624b58db", version(&pinned, &unannotated, DIGEST, "{}"));
+    }
+
+    #[test]
+    fn platform_selection() {
+        let index: OciImageIndex = serde_json::from_value(json!({
+            "schemaVersion": 2,
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:1111",
+                    "size": 1,
+                    "platform": { "os": "none", "architecture": "thumbv7em", "variant": "nrf52840" }
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:2222",
+                    "size": 1,
+                    "platform": { "os": "none", "architecture": "riscv32imc" },
+                    "annotations": { "io.drogue.ajour.platform": "esp32c3-devkit" }
+                }
+            ]
+        }))
+        .unwrap();
+
+        let digest = |platform| select_platform(&index, platform).map(|e| e.digest.as_str());
+        assert_eq!(Some("sha256:1111"), digest(Some("none/thumbv7em/nrf52840")));
+        assert_eq!(Some("sha256:2222"), digest(Some("none/riscv32imc")));
+        assert_eq!(Some("sha256:2222"), digest(Some("esp32c3-devkit")));
+        assert_eq!(None, digest(Some("none/thumbv7em")));
+        assert_eq!(None, digest(None));
+    }
 }
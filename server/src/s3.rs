@@ -0,0 +1,347 @@
+use crate::file::FileMetadata;
+use crate::metadata::Metadata;
+use crate::updater::FirmwareStore;
+use crate::validate;
+use ::s3::creds::Credentials;
+use ::s3::{Bucket, Region};
+use anyhow::anyhow;
+use lru::LruCache;
+
+/// Firmware store reading `<name>.json` and `<name>.bin` below a prefix of an S3 bucket.
+pub struct S3Client {
+    bucket: Bucket,
+    prefix: String,
+    range_size: u64,
+
+    // Cached by checksum
+    firmware_cache: LruCache<String, Vec<u8>>,
+}
+
+impl S3Client {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        bucket: &str,
+        prefix: String,
+        region: &str,
+        endpoint: Option<String>,
+        access_key: Option<String>,
+        secret_key: Option<String>,
+        range_size: u64,
+        cache_size: usize,
+    ) -> Result<Self, anyhow::Error> {
+        // Without explicit keys, use the AWS_* environment variables or profile
+        let credentials = Credentials::new(
+            access_key.as_deref(),
+            secret_key.as_deref(),
+            None,
+            None,
+            None,
+        )?;
+        let bucket = match endpoint {
+            // S3 compatible stores such as MinIO are addressed with path style URLs
+            Some(endpoint) => Bucket::new(
+                bucket,
+                Region::Custom {
+                    region: region.to_string(),
+                    endpoint,
+                },
+                credentials,
+            )?
+            .with_path_style(),
+            None => Bucket::new(bucket, region.parse()?, credentials)?,
+        };
+        Ok(Self {
+            bucket,
+            prefix,
+            range_size,
+            firmware_cache: LruCache::new(cache_size),
+        })
+    }
+
+    /// Key of a firmware below the prefix, the name must not reach into other parts of the bucket.
+    fn path(&self, name: &str, extension: &str) -> Result<String, anyhow::Error> {
+        Ok(format!(
+            "{}{}.{}",
+            self.prefix,
+            validate::file_name(name)?,
+            extension
+        ))
+    }
+}
+
+/// Fail on responses other than the expected status, rust-s3 returns them as data.
+fn expect_status(path: &str, status: u16, expected: u16) -> Result<(), anyhow::Error> {
+    if status != expected {
+        return Err(anyhow!("Unexpected status {} reading {}", status, path));
+    }
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl FirmwareStore for S3Client {
+    type Params = String;
+
+    async fn fetch_metadata(
+        &mut self,
+        params: &Self::Params,
+    ) -> Result<(Self::Context, Option<Metadata>), anyhow::Error> {
+        let path = self.path(params, "json")?;
+        log::debug!(
+            "Looking for metadata from s3://{}/{}",
+            self.bucket.name,
+            path
+        );
+        let (data, status) = self.bucket.get_object(&path).await?;
+        expect_status(&path, status, 200)?;
+        let metadata: FileMetadata = serde_json::from_slice(&data)?;
+        Ok(((), Some(metadata.into())))
+    }
+
+    async fn update_progress(
+        &mut self,
+        _: &Self::Params,
+        _: &Self::Context,
+        _: u32,
+        _: u32,
+        _: &str,
+    ) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    async fn mark_synced(
+        &mut self,
+        _: &Self::Params,
+        _: &Self::Context,
+        _: bool,
+    ) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &mut self,
+        _: &Self::Params,
+        _: &Self::Context,
+        _: &str,
+    ) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    type Context = ();
+    async fn fetch_firmware(
+        &mut self,
+        params: &Self::Params,
+        _: &Self::Context,
+        metadata: &Metadata,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        if let Some(data) = self.firmware_cache.get(&metadata.checksum) {
+            return Ok(data.clone());
+        }
+
+        let path = self.path(params, "bin")?;
+        log::debug!("Reading firmware from s3://{}/{}", self.bucket.name, path);
+        let size = metadata.size as u64;
+        let mut data = Vec::with_capacity(size as usize);
+        while (data.len() as u64) < size {
+            let start = data.len() as u64;
+            let end = core::cmp::min(start + self.range_size, size) - 1;
+            // Ranges of a single byte are rejected by rust-s3, read those up to the end instead
+            let (range, status) = self
+                .bucket
+                .get_object_range(&path, start, (end > start).then_some(end))
+                .await?;
+            expect_status(&path, status, 206)?;
+            if range.is_empty() {
+                return Err(anyhow!(
+                    "Firmware {} is smaller than the {} bytes in its metadata",
+                    path,
+                    size
+                ));
+            }
+            data.extend_from_slice(&range);
+        }
+        data.truncate(size as usize);
+        metadata.verify_checksum(&data)?;
+        self.firmware_cache
+            .put(metadata.checksum.clone(), data.clone());
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use sha2::{Digest, Sha256};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const FIRMWARE: [u8; 10] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+    fn metadata_json(firmware: &[u8]) -> String {
+        format!(
+            r#"{{"version":"1.0.0","checksum":"{}","size":{}}}"#,
+            hex::encode(Sha256::digest(firmware)),
+            firmware.len()
+        )
+    }
+
+    /// Serve objects of the `firmware` bucket with path style URLs, ignoring credentials.
+    async fn stub(objects: Vec<(&'static str, Vec<u8>)>, requests: Arc<AtomicUsize>) -> String {
+        let service = make_service_fn(move |_| {
+            let (objects, requests) = (objects.clone(), requests.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    let object = objects
+                        .iter()
+                        .find(|(k, _)| req.uri().path() == format!("/firmware/{}", k))
+                        .map(|(_, v)| v.clone());
+                    let range = req
+                        .headers()
+                        .get("range")
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.strip_prefix("bytes="))
+                        .and_then(|v| v.split_once('-'))
+                        .map(|(s, e)| (s.parse::<usize>().unwrap(), e.parse::<usize>().ok()));
+                    let response = match (object, range) {
+                        (None, _) => Response::builder()
+                            .status(404)
+                            .body(Body::from("NoSuchKey")),
+                        (Some(data), None) => {
+                            Response::builder().status(200).body(Body::from(data))
+                        }
+                        (Some(data), Some((start, end))) => Response::builder().status(206).body(
+                            Body::from(data[start..=end.unwrap_or(data.len() - 1)].to_vec()),
+                        ),
+                    };
+                    async move { Ok::<_, Infallible>(response.unwrap()) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        format!("http://{}", addr)
+    }
+
+    fn client(endpoint: String) -> S3Client {
+        S3Client::new(
+            "firmware",
+            "test/".to_string(),
+            "us-east-1",
+            Some(endpoint),
+            Some("access".to_string()),
+            Some("secret".to_string()),
+            3,
+            10,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn fetch_ranges() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let endpoint = stub(
+            vec![
+                ("test/app.json", metadata_json(&FIRMWARE).into_bytes()),
+                ("test/app.bin", FIRMWARE.to_vec()),
+                ("test/bad.json", metadata_json(&[1; 10]).into_bytes()),
+                ("test/bad.bin", vec![9; 10]),
+            ],
+            requests.clone(),
+        )
+        .await;
+        let mut client = client(endpoint);
+
+        let name = "app".to_string();
+        let (_, metadata) = client.fetch_metadata(&name).await.unwrap();
+        let metadata = metadata.unwrap();
+        assert_eq!(b"1.0.0".to_vec(), metadata.version);
+
+        // Fetched in ranges of 3 bytes, then served from the cache
+        let data = client.fetch_firmware(&name, &(), &metadata).await.unwrap();
+        assert_eq!(FIRMWARE.to_vec(), data);
+        assert_eq!(5, requests.load(Ordering::SeqCst));
+        client.fetch_firmware(&name, &(), &metadata).await.unwrap();
+        assert_eq!(5, requests.load(Ordering::SeqCst));
+
+        // Error responses are not firmware
+        let missing = "missing".to_string();
+        assert!(client.fetch_metadata(&missing).await.is_err());
+        let uncached = Metadata {
+            checksum: "sha256:00".to_string(),
+            ..metadata
+        };
+        assert!(client
+            .fetch_firmware(&missing, &(), &uncached)
+            .await
+            .is_err());
+
+        // Firmware not matching the checksum is not served
+        let bad = "bad".to_string();
+        let (_, metadata) = client.fetch_metadata(&bad).await.unwrap();
+        assert!(client
+            .fetch_firmware(&bad, &(), &metadata.unwrap())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn names_within_prefix() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let endpoint = stub(vec![], requests.clone()).await;
+        let mut client = client(endpoint);
+        for name in ["../app", "other/app", "/app", ""] {
+            assert!(
+                client.fetch_metadata(&name.to_string()).await.is_err(),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(0, requests.load(Ordering::SeqCst));
+    }
+
+    /// Run against a local MinIO instance, see the installation guide.
+    #[tokio::test]
+    #[ignore]
+    async fn fetch_from_minio() {
+        let endpoint = std::env::var("AJOUR_TEST_S3_ENDPOINT")
+            .unwrap_or_else(|_| "http://localhost:9000".to_string());
+        let bucket =
+            std::env::var("AJOUR_TEST_S3_BUCKET").unwrap_or_else(|_| "firmware".to_string());
+        let mut client = S3Client::new(
+            &bucket,
+            "test/".to_string(),
+            "us-east-1",
+            Some(endpoint),
+            Some("minioadmin".to_string()),
+            Some("minioadmin".to_string()),
+            3,
+            10,
+        )
+        .unwrap();
+
+        client
+            .bucket
+            .put_object("test/app.json", metadata_json(&FIRMWARE).as_bytes())
+            .await
+            .unwrap();
+        client
+            .bucket
+            .put_object("test/app.bin", &FIRMWARE)
+            .await
+            .unwrap();
+
+        let name = "app".to_string();
+        let (_, metadata) = client.fetch_metadata(&name).await.unwrap();
+        let metadata = metadata.unwrap();
+        assert_eq!(b"1.0.0".to_vec(), metadata.version);
+        assert_eq!(10, metadata.size);
+
+        // Fetched in ranges of 3 bytes
+        let data = client.fetch_firmware(&name, &(), &metadata).await.unwrap();
+        assert_eq!(FIRMWARE.to_vec(), data);
+    }
+}
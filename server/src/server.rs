@@ -2,180 +2,260 @@ use cloudevents::{event::AttributeValue, Data, Event};
 use embedded_update::Status;
 
 use futures::stream::StreamExt;
+use opentelemetry::trace::FutureExt;
 use paho_mqtt as mqtt;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::discovery::Discovery;
+use crate::telemetry;
+use crate::transport::Transport;
 use crate::updater::Updater;
 
+/// Backoff between attempts of the first application discovery, doubled up to the max.
+const DISCOVERY_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const DISCOVERY_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
 pub struct Server {
     client: mqtt::AsyncClient,
     group_id: Option<String>,
-    applications: Vec<String>,
+    discovery: Discovery,
+    applications: HashSet<String>,
     updater: Updater,
+    // Set once applications were discovered
+    ready: Arc<AtomicBool>,
 }
 
 impl Server {
     pub fn new(
         client: mqtt::AsyncClient,
         group_id: Option<String>,
-        applications: Vec<String>,
+        discovery: Discovery,
         updater: Updater,
+        ready: Arc<AtomicBool>,
     ) -> Self {
         Self {
             client,
             group_id,
-            applications,
+            discovery,
+            applications: HashSet::new(),
             updater,
+            ready,
+        }
+    }
+
+    fn topic(&self, application: &str) -> String {
+        if let Some(group_id) = &self.group_id {
+            format!("$shared/{}/app/{}", &group_id, &application)
+        } else {
+            format!("app/{}", &application)
+        }
+    }
+
+    /// Subscribe to applications that appeared and unsubscribe from those that disappeared.
+    async fn sync_applications(&mut self) -> Result<(), anyhow::Error> {
+        let applications = self.discovery.applications().await?;
+        for application in applications.difference(&self.applications) {
+            log::info!("Subscribing to application {}", application);
+            if let Err(e) = self.client.subscribe(self.topic(application), 1).await {
+                log::warn!("Error subscribing to application {}: {:?}", application, e);
+            }
+        }
+        for application in self.applications.difference(&applications) {
+            log::info!("Unsubscribing from application {}", application);
+            if let Err(e) = self.client.unsubscribe(self.topic(application)).await {
+                log::warn!(
+                    "Error unsubscribing from application {}: {:?}",
+                    application,
+                    e
+                );
+            }
         }
+        self.applications = applications;
+        Ok(())
     }
 
     pub async fn run(&mut self) -> Result<(), anyhow::Error> {
         let mut stream = self.client.get_stream(100);
-        for application in self.applications.iter() {
-            if let Some(group_id) = &self.group_id {
-                self.client
-                    .subscribe(format!("$shared/{}/app/{}", &group_id, &application), 1);
-            } else {
-                self.client.subscribe(format!("app/{}", &application), 1);
-            }
+
+        // Not ready without any subscriptions, keep trying until discovery succeeds
+        let mut backoff = DISCOVERY_INITIAL_BACKOFF;
+        while let Err(e) = self.sync_applications().await {
+            log::warn!(
+                "Error discovering applications, retrying in {:?}: {:?}",
+                backoff,
+                e
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(DISCOVERY_MAX_BACKOFF);
         }
+        self.ready.store(true, Ordering::Relaxed);
+        log::info!("Server subscribed to applications: {:?}", self.applications);
+
+        let mut discovery = self.discovery.interval().map(|i| {
+            let mut interval = tokio::time::interval(i);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
         loop {
-            if let Some(m) = stream.next().await {
-                if let Some(m) = m {
-                    match serde_json::from_slice::<Event>(m.payload()) {
-                        Ok(e) => {
-                            let mut application = String::new();
-                            let mut device = String::new();
-                            let mut sender = String::new();
-                            let mut subject = String::new();
-                            for a in e.iter() {
-                                log::trace!("Attribute {:?}", a);
-                                if a.0 == "subject" {
-                                    if let AttributeValue::String(s) = a.1 {
-                                        subject = s.to_string();
-                                    }
-                                } else if a.0 == "device" {
-                                    if let AttributeValue::String(d) = a.1 {
-                                        device = d.to_string();
-                                    }
-                                } else if a.0 == "application" {
-                                    if let AttributeValue::String(d) = a.1 {
-                                        application = d.to_string();
-                                    }
-                                } else if a.0 == "sender" {
-                                    if let AttributeValue::String(d) = a.1 {
-                                        sender = d.to_string();
-                                    }
-                                }
+            tokio::select! {
+                m = stream.next() => {
+                    if let Some(Some(m)) = m {
+                        match serde_json::from_slice::<Event>(m.payload()) {
+                            Ok(e) => self.handle_event(e).await,
+                            Err(e) => {
+                                log::warn!("Error parsing event: {:?}", e);
+                                break;
                             }
+                        }
+                    }
+                }
+                _ = async { discovery.as_mut().unwrap().tick().await }, if discovery.is_some() => {
+                    if let Err(e) = self.sync_applications().await {
+                        log::warn!("Error discovering applications: {:?}", e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
-                            let is_dfu = if sender == "ttn-gateway" {
-                                if subject == "223" {
-                                    subject = format!("port:{}", subject);
-                                    true
-                                } else {
-                                    false
-                                }
-                            } else {
-                                subject == "dfu"
-                            };
-
-                            log::trace!(
-                                "Event from app {}, device {}, is dfu: {}",
-                                application,
-                                device,
-                                is_dfu
-                            );
-
-                            if is_dfu {
-                                let mut temporary = Vec::new();
-                                let status: Option<Result<Status, anyhow::Error>> = if let Some(d) =
-                                    e.data()
-                                {
-                                    match d {
-                                        Data::Binary(b) => Some(
-                                            serde_cbor::from_slice(&b[..]).map_err(|e| e.into()),
-                                        ),
-                                        Data::String(s) => {
-                                            Some(serde_json::from_str(&s).map_err(|e| e.into()))
-                                        }
-                                        Data::Json(v) => {
-                                            // Extract lorawan payload
-                                            if sender == "ttn-gateway" {
-                                                // TODO: Refactor/make it functional
-                                                if let Some(uplink) = v.get("uplink_message") {
-                                                    if let Some(frm) = uplink.get("frm_payload") {
-                                                        if let Some(s) = frm.as_str() {
-                                                            if let Ok(b) = base64::decode(s) {
-                                                                temporary.extend_from_slice(&b[..]);
-                                                                let s: Option<Status> =
-                                                                    serde_cbor::from_slice(
-                                                                        &temporary[..],
-                                                                    )
-                                                                    .unwrap_or(None);
-                                                                s.map(|s| Ok(s))
-                                                            } else {
-                                                                None
-                                                            }
-                                                        } else {
-                                                            None
-                                                        }
-                                                    } else {
-                                                        None
-                                                    }
-                                                } else {
-                                                    None
-                                                }
-                                            } else {
-                                                Some(
-                                                    serde_json::from_str(v.as_str().unwrap())
-                                                        .map_err(|e| e.into()),
-                                                )
-                                            }
+    async fn handle_event(&mut self, e: Event) {
+        let mut application = String::new();
+        let mut device = String::new();
+        let mut sender = String::new();
+        let mut subject = String::new();
+        for a in e.iter() {
+            log::trace!("Attribute {:?}", a);
+            if a.0 == "subject" {
+                if let AttributeValue::String(s) = a.1 {
+                    subject = s.to_string();
+                }
+            } else if a.0 == "device" {
+                if let AttributeValue::String(d) = a.1 {
+                    device = d.to_string();
+                }
+            } else if a.0 == "application" {
+                if let AttributeValue::String(d) = a.1 {
+                    application = d.to_string();
+                }
+            } else if a.0 == "sender" {
+                if let AttributeValue::String(d) = a.1 {
+                    sender = d.to_string();
+                }
+            }
+        }
+
+        // Devices with multiple firmware components report each on a "dfu/<component>" subject
+        let component = subject.strip_prefix("dfu/").map(|c| c.to_string());
+        let is_dfu = if sender == "ttn-gateway" {
+            if subject == "223" {
+                subject = format!("port:{}", subject);
+                true
+            } else {
+                false
+            }
+        } else {
+            subject == "dfu" || component.is_some()
+        };
+
+        log::trace!(
+            "Event from app {}, device {}, is dfu: {}",
+            application,
+            device,
+            is_dfu
+        );
+
+        if is_dfu {
+            let mut temporary = Vec::new();
+            let mut transport = Transport::default();
+            let status: Option<Result<Status, anyhow::Error>> = if let Some(d) = e.data() {
+                match d {
+                    Data::Binary(b) => Some(serde_cbor::from_slice(&b[..]).map_err(|e| e.into())),
+                    Data::String(s) => Some(serde_json::from_str(s).map_err(|e| e.into())),
+                    Data::Json(v) => {
+                        // Extract lorawan payload
+                        if sender == "ttn-gateway" {
+                            // TODO: Refactor/make it functional
+                            if let Some(uplink) = v.get("uplink_message") {
+                                transport = Transport::ttn(uplink);
+                                if let Some(frm) = uplink.get("frm_payload") {
+                                    if let Some(s) = frm.as_str() {
+                                        if let Ok(b) = base64::decode(s) {
+                                            temporary.extend_from_slice(&b[..]);
+                                            let s: Option<Status> =
+                                                serde_cbor::from_slice(&temporary[..])
+                                                    .unwrap_or(None);
+                                            s.map(Ok)
+                                        } else {
+                                            None
                                         }
+                                    } else {
+                                        None
                                     }
                                 } else {
                                     None
-                                };
-
-                                log::trace!("Status decode: {:?}", status);
-
-                                if let Some(Ok(status)) = status {
-                                    log::info!(
-                                        "Device {}/{} running version {:?}",
-                                        application,
-                                        device,
-                                        status.version
-                                    );
-                                    log::debug!("Received status from {}: {:?}", device, status);
-                                    if let Ok(command) =
-                                        self.updater.process(&application, &device, &status).await
-                                    {
-                                        //log::trace!("Sending command to {}: {:?}", device, command);
-
-                                        let topic = format!(
-                                            "command/{}/{}/{}",
-                                            application, device, subject
-                                        );
-                                        let message =
-                                            mqtt::Message::new(topic, command.as_bytes(), 1);
-                                        if let Err(e) = self.client.publish(message).await {
-                                            log::warn!(
-                                                "Error publishing command back to device: {:?}",
-                                                e
-                                            );
-                                        }
-                                    }
                                 }
+                            } else {
+                                None
                             }
+                        } else {
+                            Some(serde_json::from_str(v.as_str().unwrap()).map_err(|e| e.into()))
                         }
-                        Err(e) => {
-                            log::warn!("Error parsing event: {:?}", e);
-                            break;
+                    }
+                }
+            } else {
+                None
+            };
+
+            log::trace!("Status decode: {:?}", status);
+
+            if let Some(Ok(status)) = status {
+                log::info!(
+                    "Device {}/{} running version {:?}",
+                    application,
+                    device,
+                    status.version
+                );
+                log::debug!("Received status from {}: {:?}", device, status);
+                let cx = telemetry::start_span(
+                    "Server::process_event",
+                    &telemetry::event_context(&e),
+                    telemetry::device_attributes(&application, &device),
+                );
+                let client = &self.client;
+                let updater = &mut self.updater;
+                async {
+                    if let Ok(command) = updater
+                        .process(
+                            &application,
+                            &device,
+                            component.as_deref(),
+                            &status,
+                            &transport,
+                        )
+                        .await
+                    {
+                        //log::trace!("Sending command to {}: {:?}", device, command);
+
+                        let topic = format!("command/{}/{}/{}", application, device, subject);
+                        let message = mqtt::Message::new(topic, command.as_bytes(), 1);
+                        if let Err(e) = telemetry::traced(
+                            "Server::publish",
+                            telemetry::device_attributes(&application, &device),
+                            async { Ok(client.publish(message).await?) },
+                        )
+                        .await
+                        {
+                            log::warn!("Error publishing command back to device: {:?}", e);
                         }
                     }
                 }
+                .with_context(cx)
+                .await;
             }
         }
-        Ok(())
     }
 }
@@ -0,0 +1,114 @@
+use cloudevents::{event::ExtensionValue, Event};
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    sdk::{self, propagation::TraceContextPropagator, Resource},
+    trace::{FutureExt, StatusCode, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use serde::Deserialize;
+use std::future::Future;
+
+const TRACER_NAME: &str = "drogue-ajour";
+
+#[derive(clap::ArgEnum, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TracingExporter {
+    None,
+    Stdout,
+    Otlp,
+}
+
+/// Install the global tracer provider and the W3C trace context propagator.
+pub fn init(exporter: TracingExporter, endpoint: Option<String>) -> Result<(), anyhow::Error> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let config = sdk::trace::config().with_resource(Resource::new(vec![KeyValue::new(
+        "service.name",
+        env!("CARGO_PKG_NAME"),
+    )]));
+    match exporter {
+        TracingExporter::None => {}
+        TracingExporter::Stdout => {
+            log::info!("Exporting traces to stdout");
+            sdk::export::trace::stdout::new_pipeline()
+                .with_trace_config(config)
+                .install_simple();
+        }
+        TracingExporter::Otlp => {
+            let mut otlp = opentelemetry_otlp::new_exporter().tonic();
+            if let Some(endpoint) = endpoint {
+                log::info!("Exporting traces to OTLP collector at {}", endpoint);
+                otlp = otlp.with_endpoint(endpoint);
+            }
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(otlp)
+                .with_trace_config(config)
+                .install_batch(opentelemetry::runtime::Tokio)?;
+        }
+    }
+    Ok(())
+}
+
+/// Flush any pending spans before exiting.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Extract trace context from the CloudEvents distributed tracing extension (`traceparent` and `tracestate`).
+pub fn event_context(event: &Event) -> Context {
+    global::get_text_map_propagator(|p| p.extract(&EventExtractor(event)))
+}
+
+struct EventExtractor<'a>(&'a Event);
+
+impl<'a> Extractor for EventExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match self.0.extension(key) {
+            Some(ExtensionValue::String(s)) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter_extensions().map(|(k, _)| k).collect()
+    }
+}
+
+/// Span attributes identifying a device.
+pub fn device_attributes(application: &str, device: &str) -> Vec<KeyValue> {
+    vec![
+        KeyValue::new("application", application.to_string()),
+        KeyValue::new("device", device.to_string()),
+    ]
+}
+
+/// Start a span which is a child of the given context.
+pub fn start_span(name: &'static str, parent: &Context, attributes: Vec<KeyValue>) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, parent);
+    parent.with_span(span)
+}
+
+/// Run a future within a new span that is a child of the current context, recording any error on the span.
+pub async fn traced<T, F>(
+    name: &'static str,
+    attributes: Vec<KeyValue>,
+    f: F,
+) -> Result<T, anyhow::Error>
+where
+    F: Future<Output = Result<T, anyhow::Error>>,
+{
+    let cx = start_span(name, &Context::current(), attributes);
+    let result = f.with_context(cx.clone()).await;
+    if let Err(e) = &result {
+        let span = cx.span();
+        span.record_exception(e.as_ref());
+        span.set_status(StatusCode::Error, e.to_string());
+    }
+    result
+}
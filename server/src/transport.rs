@@ -0,0 +1,283 @@
+use serde_json::Value;
+
+/// Block size used when the device does not report its own.
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Limits of the transport a device sends its status through, and receives commands from.
+#[derive(Debug, Clone, Default)]
+pub struct Transport {
+    /// Largest command the transport can deliver to the device, in bytes
+    pub max_payload: Option<usize>,
+}
+
+impl Transport {
+    /// LoRaWAN through The Things Network, limited by the band and data rate of the uplink.
+    ///
+    /// Downlinks in the first receive window use a data rate derived from the one of the uplink,
+    /// so the application payload limit of that data rate in the band of the uplink applies. The
+    /// band is taken from the band or frequency plan ID of the uplink, or else its frequency.
+    pub fn ttn(uplink: &Value) -> Self {
+        let settings = uplink.get("settings");
+        let id = uplink
+            .pointer("/version_ids/band_id")
+            .or_else(|| uplink.get("frequency_plan_id"))
+            .and_then(|id| id.as_str());
+        let frequency = settings.and_then(|s| s.get("frequency")).and_then(|f| {
+            f.as_u64()
+                .or_else(|| f.as_str().and_then(|f| f.parse().ok()))
+        });
+        let bands = match (id, frequency) {
+            (Some(id), _) => Band::from_id(id).into_iter().collect(),
+            (None, Some(frequency)) => Band::from_frequency(frequency),
+            (None, None) => Vec::new(),
+        };
+
+        let lora = settings.and_then(|s| s.pointer("/data_rate/lora"));
+        let rate = lora
+            .and_then(|l| l.get("spreading_factor"))
+            .and_then(|s| s.as_u64())
+            .map(|sf| {
+                let bandwidth = lora
+                    .and_then(|l| l.get("bandwidth"))
+                    .and_then(|b| b.as_u64())
+                    .unwrap_or(125_000);
+                (sf, bandwidth)
+            });
+        let index = settings
+            .and_then(|s| s.get("data_rate_index"))
+            .and_then(|i| i.as_u64());
+
+        // Ambiguous bands get the smallest of their limits, unknown ones the smallest of all
+        let max_payload = bands
+            .iter()
+            .filter_map(|band| {
+                rate.or_else(|| index.and_then(|i| band.uplink_rate(i)))
+                    .and_then(|(sf, bandwidth)| band.max_payload(sf, bandwidth))
+            })
+            .min()
+            .unwrap_or(UNKNOWN_MAX_PAYLOAD);
+        Self {
+            max_payload: Some(max_payload),
+        }
+    }
+}
+
+/// Smallest downlink payload limit of the slowest data rates in all bands.
+const UNKNOWN_MAX_PAYLOAD: usize = 33;
+
+/// LoRaWAN regional bands, with their downlink payload limits.
+///
+/// Limits are the repeater compatible maximum application payload sizes (N) of the LoRaWAN
+/// Regional Parameters RP002-1.0.3, for AU915 with the downlink dwell time limit The Things
+/// Network uses. They assume that no MAC commands are sent in the FOpts field, those reduce the
+/// room for the payload and are left to the network server to send in a separate downlink.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Band {
+    EU868,
+    US915,
+    AU915,
+}
+
+impl Band {
+    /// Band of a band or frequency plan ID, such as `EU_863_870` or `US_902_928_FSB_2`.
+    fn from_id(id: &str) -> Option<Self> {
+        if id.starts_with("EU_863_870") {
+            Some(Self::EU868)
+        } else if id.starts_with("US_902_928") {
+            Some(Self::US915)
+        } else if id.starts_with("AU_915_928") {
+            Some(Self::AU915)
+        } else {
+            None
+        }
+    }
+
+    /// Bands using an uplink frequency in Hz.
+    fn from_frequency(frequency: u64) -> Vec<Self> {
+        match frequency {
+            863_000_000..=870_000_000 => vec![Self::EU868],
+            902_000_000..=914_999_999 => vec![Self::US915],
+            915_000_000..=928_000_000 => vec![Self::US915, Self::AU915],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Spreading factor and bandwidth of an uplink data rate.
+    fn uplink_rate(&self, index: u64) -> Option<(u64, u64)> {
+        match (self, index) {
+            (Self::EU868 | Self::AU915, 0..=5) => Some((12 - index, 125_000)),
+            (Self::US915, 0..=3) => Some((10 - index, 125_000)),
+            (Self::US915, 4) | (Self::AU915, 6) => Some((8, 500_000)),
+            _ => None,
+        }
+    }
+
+    /// Application payload limit of a downlink in the first receive window, after an uplink at
+    /// the given spreading factor and bandwidth.
+    fn max_payload(&self, sf: u64, bandwidth: u64) -> Option<usize> {
+        match (self, sf, bandwidth) {
+            // Downlinks use the data rate of the uplink
+            (Self::EU868, 7 | 8, 125_000) => Some(222),
+            (Self::EU868, 9, 125_000) => Some(115),
+            (Self::EU868, 10..=12, 125_000) => Some(51),
+            // Downlinks use the 500 kHz data rates, 10 above the one of the uplink
+            (Self::US915, 7..=10, 125_000) | (Self::US915, 8, 500_000) => Some(222),
+            // Downlinks use the 500 kHz data rates, 8 above the one of the uplink
+            (Self::AU915, 7..=10, 125_000) | (Self::AU915, 8, 500_000) => Some(222),
+            (Self::AU915, 11, 125_000) => Some(109),
+            (Self::AU915, 12, 125_000) => Some(33),
+            _ => None,
+        }
+    }
+}
+
+/// Largest block size up to `max` for which the encoded command fits into `max_payload`.
+///
+/// The length of the command encoding a block of a given size is computed by `encoded`,
+/// accounting for the framing overhead of the command.
+pub fn block_size<F>(max_payload: usize, max: usize, encoded: F) -> usize
+where
+    F: Fn(usize) -> usize,
+{
+    if encoded(max) <= max_payload {
+        return max;
+    }
+    // Encoded length grows with the block size, search for the last one that fits
+    let (mut low, mut high) = (0, max);
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if encoded(mid) <= max_payload {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    if encoded(low) <= max_payload {
+        low
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn ttn_data_rates() {
+        let uplink = json!({
+            "frm_payload": "oA==",
+            "settings": {
+                "data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 7}},
+                "frequency": "868100000",
+            },
+        });
+        assert_eq!(Some(222), Transport::ttn(&uplink).max_payload);
+
+        let uplink = json!({
+            "settings": {"data_rate": {"lora": {"spreading_factor": 9}}},
+            "frequency_plan_id": "EU_863_870_TTN",
+        });
+        assert_eq!(Some(115), Transport::ttn(&uplink).max_payload);
+
+        let eu868 = |index| {
+            json!({
+                "settings": {"data_rate_index": index},
+                "version_ids": {"band_id": "EU_863_870"},
+            })
+        };
+        assert_eq!(Some(51), Transport::ttn(&eu868(0)).max_payload);
+        assert_eq!(Some(222), Transport::ttn(&eu868(5)).max_payload);
+        assert_eq!(Some(33), Transport::ttn(&eu868(7)).max_payload);
+
+        // Without a band, any data rate gets the smallest limit
+        let uplink = json!({"settings": {"data_rate_index": 5}});
+        assert_eq!(Some(33), Transport::ttn(&uplink).max_payload);
+        assert_eq!(Some(33), Transport::ttn(&json!({})).max_payload);
+    }
+
+    #[test]
+    fn ttn_bands() {
+        // US915 downlinks use faster data rates than the uplink
+        let uplink = json!({
+            "settings": {
+                "data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 10}},
+                "frequency": "904300000",
+            },
+        });
+        assert_eq!(Some(222), Transport::ttn(&uplink).max_payload);
+        let uplink = json!({
+            "settings": {"data_rate_index": 0},
+            "version_ids": {"band_id": "US_902_928"},
+        });
+        assert_eq!(Some(222), Transport::ttn(&uplink).max_payload);
+
+        // AU915 by frequency plan, or by frequency shared with US915
+        let uplink = json!({
+            "frequency_plan_id": "AU_915_928_FSB_2",
+            "settings": {"data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 12}}},
+        });
+        assert_eq!(Some(33), Transport::ttn(&uplink).max_payload);
+        let uplink = json!({
+            "settings": {
+                "data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 11}},
+                "frequency": "917000000",
+            },
+        });
+        assert_eq!(Some(109), Transport::ttn(&uplink).max_payload);
+        let uplink = json!({
+            "settings": {
+                "data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 7}},
+                "frequency": 917000000,
+            },
+        });
+        assert_eq!(Some(222), Transport::ttn(&uplink).max_payload);
+
+        // EU868 by frequency
+        let uplink = json!({
+            "settings": {
+                "data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 9}},
+                "frequency": "868100000",
+            },
+        });
+        assert_eq!(Some(115), Transport::ttn(&uplink).max_payload);
+
+        // Other bands, and data rates not in the band, get the smallest limit
+        let uplink = json!({
+            "settings": {
+                "data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 7}},
+                "frequency": "923200000",
+            },
+            "version_ids": {"band_id": "AS_923"},
+        });
+        assert_eq!(Some(33), Transport::ttn(&uplink).max_payload);
+        let uplink = json!({
+            "settings": {
+                "data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 7}},
+                "frequency": "433175000",
+            },
+        });
+        assert_eq!(Some(33), Transport::ttn(&uplink).max_payload);
+        let uplink = json!({
+            "settings": {
+                "data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 12}},
+                "frequency": "904300000",
+            },
+        });
+        assert_eq!(Some(33), Transport::ttn(&uplink).max_payload);
+    }
+
+    #[test]
+    fn block_framing() {
+        // Fixed overhead, plus a longer length prefix from 24 bytes on
+        let encoded = |n: usize| 20 + n + if n < 24 { 1 } else { 2 };
+        assert_eq!(512, block_size(1024, 512, encoded));
+        assert_eq!(29, block_size(51, 512, encoded));
+        assert_eq!(200, block_size(222, 512, encoded));
+        assert_eq!(23, block_size(44, 512, encoded));
+        assert_eq!(23, block_size(45, 512, encoded));
+        assert_eq!(24, block_size(46, 512, encoded));
+        assert_eq!(0, block_size(20, 512, encoded));
+    }
+}
@@ -1,93 +1,282 @@
 use anyhow::anyhow;
+use lru::LruCache;
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use ajour_schema::*;
 use embedded_update::{Command, Status};
 
+use crate::config::{ApplicationConfig, DEFAULT_STORE};
 use crate::file::FileClient;
-use crate::hawkbit::HawkbitClient;
-use crate::index::Index;
-use crate::metadata::Metadata;
-use crate::oci::OciClient;
+use crate::hawkbit::{controller_attributes, controller_id, HawkbitClient, HawkbitParams};
+use crate::http::HttpClient;
+use crate::index::{Component, Index, Resources};
+use crate::metadata::{Metadata, Verification};
+use crate::oci::{OciClient, OciParams};
+use crate::s3::S3Client;
+use crate::telemetry;
+use crate::transport::{self, Transport, DEFAULT_BLOCK_SIZE};
+
+/// Named firmware stores, by store type.
+#[derive(Default)]
+pub struct Stores {
+    pub oci: HashMap<String, OciClient>,
+    pub hawkbit: HashMap<String, HawkbitClient>,
+    pub file: HashMap<String, FileClient>,
+    pub http: HashMap<String, HttpClient>,
+    pub s3: HashMap<String, S3Client>,
+}
 
 pub struct Updater {
     index: Index,
-    oci: Option<OciClient>,
-    hawkbit: Option<HawkbitClient>,
-    file: Option<FileClient>,
+    stores: Stores,
+    applications: HashMap<String, ApplicationConfig>,
+    attempts: Attempts,
+    retry: RetryPolicy,
+    poll: PollPolicy,
+    // Consecutive errors, by application and device
+    failures: LruCache<(String, String), Failures>,
+}
+
+/// Backoff of devices after errors processing their status.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Backoff after the first error, doubled for every consecutive error
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Number of consecutive errors, or failed installations of a version, before marking the
+    /// update of a device as failed
+    pub budget: u32,
+    /// Number of devices to keep errors and ongoing updates of
+    pub devices_max: usize,
+}
+
+impl RetryPolicy {
+    fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Poll interval sent to devices in sync and wait commands.
+#[derive(Debug, Clone)]
+pub struct PollPolicy {
+    /// Poll interval in seconds, unless set for the application or device
+    pub interval: Option<u32>,
+    /// Fraction of the interval randomly added or subtracted, to spread out devices checking in
+    pub jitter: f32,
+}
+
+impl PollPolicy {
+    fn interval(&self, interval: Option<u32>) -> Option<u32> {
+        interval.or(self.interval).map(|interval| {
+            let spread = interval as f32 * self.jitter;
+            let jitter = if spread > 0.0 {
+                rand::thread_rng().gen_range(-spread..=spread)
+            } else {
+                0.0
+            };
+            (interval as f32 + jitter).round().max(1.0) as u32
+        })
+    }
+}
+
+struct Failures {
+    count: u32,
+    until: Instant,
+}
+
+/// Ongoing updates, by application, device and component.
+type Attempts = LruCache<(String, String, Option<String>), Attempt>;
+
+/// Error for firmware of a store type that is not configured.
+fn missing_store(application: &str, device: &str, kind: &str) -> anyhow::Error {
+    let e = format!(
+        "Device {}/{} requested {} firmware, but no {} store is configured",
+        application, device, kind, kind
+    );
+    log::warn!("{}", e);
+    anyhow!("{}", e)
+}
+
+/// Select the store configured for an application, or the default store.
+fn select<'m, T>(stores: &'m mut HashMap<String, T>, name: Option<&String>) -> Option<&'m mut T> {
+    stores.get_mut(name.map(|s| s.as_str()).unwrap_or(DEFAULT_STORE))
 }
 
 impl Updater {
     pub fn new(
         index: Index,
-        oci: Option<OciClient>,
-        hawkbit: Option<HawkbitClient>,
-        file: Option<FileClient>,
+        stores: Stores,
+        applications: HashMap<String, ApplicationConfig>,
+        retry: RetryPolicy,
+        poll: PollPolicy,
     ) -> Self {
         Self {
-            oci,
             index,
-            hawkbit,
-            file,
+            stores,
+            applications,
+            attempts: LruCache::new(retry.devices_max),
+            failures: LruCache::new(retry.devices_max),
+            retry,
+            poll,
         }
     }
+
+    /// Process the status of a device, asking the device to back off after errors.
     pub async fn process<'a>(
         &mut self,
         application: &str,
         device: &str,
+        component: Option<&str>,
         status: &'a Status<'a>,
+        transport: &Transport,
     ) -> Result<SerializedCommand, anyhow::Error> {
-        if let Some(spec) = self.index.latest_version(application, device).await? {
-            let index = &mut self.index;
-            match spec {
-                FirmwareSpec::OCI {
-                    image,
-                    image_pull_policy,
-                    build: _,
-                } => {
-                    if let Some(oci) = self.oci.as_mut() {
-                        Self::process_update(
-                            oci,
-                            index,
+        let key = (application.to_string(), device.to_string());
+        if let Some(failures) = self.failures.peek(&key) {
+            let now = Instant::now();
+            if failures.until > now {
+                let wait = (failures.until - now).as_secs_f64().ceil() as u32;
+                return Ok(Command::new_wait(Some(wait), status.correlation_id).try_into()?);
+            }
+        }
+
+        match self
+            .process_status(application, device, component, status, transport)
+            .await
+        {
+            Ok(command) => {
+                if let Some(failures) = self.failures.pop(&key) {
+                    if failures.count >= self.retry.budget {
+                        if let Err(e) = self
+                            .index
+                            .set_update_failed(application, device, None)
+                            .await
+                        {
+                            log::warn!(
+                                "Error updating status of device {}/{}: {:?}",
+                                application,
+                                device,
+                                e
+                            );
+                        }
+                    }
+                }
+                Ok(command)
+            }
+            Err(e) => {
+                let count = self.failures.peek(&key).map(|f| f.count).unwrap_or(0) + 1;
+                let backoff = self.retry.backoff(count);
+                self.failures.put(
+                    key,
+                    Failures {
+                        count,
+                        until: Instant::now() + backoff,
+                    },
+                );
+                log::warn!(
+                    "Error processing status of device {}/{} ({} consecutive), retrying in {:?}: {:?}",
+                    application,
+                    device,
+                    count,
+                    backoff,
+                    e
+                );
+
+                if count == self.retry.budget {
+                    if let Err(e) = self
+                        .index
+                        .set_update_failed(application, device, Some(e.to_string()))
+                        .await
+                    {
+                        log::warn!(
+                            "Error updating status of device {}/{}: {:?}",
                             application,
                             device,
-                            &status,
-                            &(image.to_string(), image_pull_policy),
-                        )
-                        .await
-                    } else {
-                        let e = format!(
-                            "Device {}/{} requested container firmware, but no container registry configured",
-                            application, device
+                            e
                         );
-                        log::warn!("{}", e);
-                        Err(anyhow!("{}", e))
                     }
                 }
-                FirmwareSpec::HAWKBIT { controller } => {
-                    if let Some(hb) = self.hawkbit.as_mut() {
-                        hb.register(&controller).await?;
-                        Self::process_update(hb, index, application, device, &status, &controller)
-                            .await
-                    } else {
-                        let e = format!(
-                            "Device {}/{} requested Hawkbit firmware, but no Hawkbit configured",
-                            application, device
-                        );
-                        log::warn!("{}", e);
-                        Err(anyhow!("{}", e))
-                    }
+                Ok(
+                    Command::new_wait(Some(backoff.as_secs() as u32), status.correlation_id)
+                        .try_into()?,
+                )
+            }
+        }
+    }
+
+    async fn process_status<'a>(
+        &mut self,
+        application: &str,
+        device: &str,
+        component: Option<&str>,
+        status: &'a Status<'a>,
+        transport: &Transport,
+    ) -> Result<SerializedCommand, anyhow::Error> {
+        let resources = self.index.resources(application, device).await?;
+        if let Some(spec) = resources.latest_version()? {
+            let (spec, poll_interval) = Self::resolve_channel(&resources, application, spec)?;
+            match (spec, component) {
+                (FirmwareSpec::COMPONENTS(components), Some(name)) => {
+                    let names: Vec<String> = components.iter().map(|c| c.name.clone()).collect();
+                    let position = names.iter().position(|n| n == name).ok_or_else(|| {
+                        anyhow!(
+                            "Device {}/{} reported unknown component {}",
+                            application,
+                            device,
+                            name
+                        )
+                    })?;
+
+                    // Components are updated in order, only swap once the previous ones are in sync
+                    let hold_swap = !resources.components_in_sync(&names[..position])?;
+                    let (spec, component_interval) = Self::resolve_channel(
+                        &resources,
+                        application,
+                        components[position].firmware.clone(),
+                    )?;
+                    let update = ComponentUpdate {
+                        component: Component { name, all: &names },
+                        hold_swap,
+                    };
+                    self.process_spec(
+                        application,
+                        device,
+                        &resources,
+                        Some(&update),
+                        spec,
+                        poll_interval.or(component_interval),
+                        status,
+                        transport,
+                    )
+                    .await
                 }
-                FirmwareSpec::FILE { name } => {
-                    if let Some(f) = self.file.as_mut() {
-                        Self::process_update(f, index, application, device, &status, &name).await
-                    } else {
-                        let e = format!(
-                            "Device {}/{} requested firmware from file, but no file registry configured",
-                            application, device
-                        );
-                        log::warn!("{}", e);
-                        Err(anyhow!("{}", e))
-                    }
+                (FirmwareSpec::COMPONENTS(_), None) => Err(anyhow!(
+                    "Device {}/{} has firmware components, but reported status without component",
+                    application,
+                    device
+                )),
+                (_, Some(name)) => Err(anyhow!(
+                    "Device {}/{} reported status for component {}, but has no firmware components",
+                    application,
+                    device,
+                    name
+                )),
+                (spec, None) => {
+                    self.process_spec(
+                        application,
+                        device,
+                        &resources,
+                        None,
+                        spec,
+                        poll_interval,
+                        status,
+                        transport,
+                    )
+                    .await
                 }
             }
         } else {
@@ -95,22 +284,255 @@ impl Updater {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
+    async fn process_spec<'a>(
+        &mut self,
+        application: &str,
+        device: &str,
+        resources: &Resources,
+        component: Option<&ComponentUpdate<'_>>,
+        spec: FirmwareSpec,
+        poll_interval: Option<u32>,
+        status: &'a Status<'a>,
+        transport: &Transport,
+    ) -> Result<SerializedCommand, anyhow::Error> {
+        let delivery = Delivery {
+            poll: self.poll.interval(poll_interval),
+            max_payload: transport.max_payload,
+            last_try: self
+                .failures
+                .peek(&(application.to_string(), device.to_string()))
+                .map(|f| f.count + 1)
+                .unwrap_or(1)
+                >= self.retry.budget,
+            budget: self.retry.budget,
+        };
+        let index = &mut self.index;
+        let attempts = &mut self.attempts;
+        let app_config = self.applications.get(application);
+        match spec {
+            FirmwareSpec::OCI {
+                image,
+                image_pull_policy,
+                build: _,
+                registry,
+                poll_interval: _,
+            } => {
+                if let Some(oci) = select(
+                    &mut self.stores.oci,
+                    app_config.and_then(|c| c.oci.as_ref()),
+                ) {
+                    let platform = resources.device_label(oci.platform_label());
+                    let params = OciParams {
+                        image,
+                        registry,
+                        image_pull_policy,
+                        signature_policy: app_config.and_then(|c| c.signature_policy),
+                        platform,
+                    };
+                    Self::process_update(
+                        oci,
+                        index,
+                        attempts,
+                        application,
+                        device,
+                        component,
+                        status,
+                        &delivery,
+                        &params,
+                    )
+                    .await
+                } else {
+                    Err(missing_store(application, device, "OCI"))
+                }
+            }
+            FirmwareSpec::HAWKBIT {
+                controller,
+                url,
+                tenant,
+                secret,
+                attributes,
+                part,
+                artifact,
+                poll_interval: _,
+            } => {
+                if let Some(hb) = select(
+                    &mut self.stores.hawkbit,
+                    app_config.and_then(|c| c.hawkbit.as_ref()),
+                ) {
+                    let d = resources.device.as_ref();
+                    let params = HawkbitParams {
+                        controller: controller_id(&controller, application, device, d)?,
+                        url,
+                        tenant,
+                        secret,
+                        part,
+                        artifact,
+                    };
+                    let attributes = controller_attributes(&attributes, d, status.version.as_ref());
+                    telemetry::traced(
+                        "HawkbitClient::register",
+                        telemetry::device_attributes(application, device),
+                        hb.register(&params, attributes),
+                    )
+                    .await?;
+                    Self::process_update(
+                        hb,
+                        index,
+                        attempts,
+                        application,
+                        device,
+                        component,
+                        status,
+                        &delivery,
+                        &params,
+                    )
+                    .await
+                } else {
+                    Err(missing_store(application, device, "Hawkbit"))
+                }
+            }
+            FirmwareSpec::FILE { name, version, .. } => {
+                if let Some(f) = select(
+                    &mut self.stores.file,
+                    app_config.and_then(|c| c.file.as_ref()),
+                ) {
+                    Self::process_update(
+                        f,
+                        index,
+                        attempts,
+                        application,
+                        device,
+                        component,
+                        status,
+                        &delivery,
+                        &(name, version),
+                    )
+                    .await
+                } else {
+                    Err(missing_store(application, device, "file"))
+                }
+            }
+            FirmwareSpec::HTTP {
+                metadata,
+                binary,
+                secret,
+                poll_interval: _,
+            } => {
+                if let Some(http) = select(
+                    &mut self.stores.http,
+                    app_config.and_then(|c| c.http.as_ref()),
+                ) {
+                    Self::process_update(
+                        http,
+                        index,
+                        attempts,
+                        application,
+                        device,
+                        component,
+                        status,
+                        &delivery,
+                        &(metadata, binary, secret),
+                    )
+                    .await
+                } else {
+                    Err(missing_store(application, device, "HTTP"))
+                }
+            }
+            FirmwareSpec::S3 { name, .. } => {
+                if let Some(s3) =
+                    select(&mut self.stores.s3, app_config.and_then(|c| c.s3.as_ref()))
+                {
+                    Self::process_update(
+                        s3,
+                        index,
+                        attempts,
+                        application,
+                        device,
+                        component,
+                        status,
+                        &delivery,
+                        &name,
+                    )
+                    .await
+                } else {
+                    Err(missing_store(application, device, "S3"))
+                }
+            }
+            FirmwareSpec::COMPONENTS(_) => Err(anyhow!(
+                "Device {}/{} has nested firmware components",
+                application,
+                device
+            )),
+            FirmwareSpec::CHANNEL { name, .. } => Err(anyhow!(
+                "Channel {} of application {} refers to another channel",
+                name,
+                application
+            )),
+        }
+    }
+
+    /// Replace a channel reference with the firmware currently assigned to the channel.
+    ///
+    /// Also returns the poll interval of the reference, or else of the channel firmware.
+    fn resolve_channel(
+        resources: &Resources,
+        application: &str,
+        spec: FirmwareSpec,
+    ) -> Result<(FirmwareSpec, Option<u32>), anyhow::Error> {
+        if let FirmwareSpec::CHANNEL {
+            name,
+            poll_interval,
+        } = spec
+        {
+            let resolved = resources.channel(&name)?.ok_or_else(|| {
+                let e = format!(
+                    "Channel {} is not defined for application {}",
+                    name, application
+                );
+                log::warn!("{}", e);
+                anyhow!("{}", e)
+            })?;
+            let poll_interval = poll_interval.or_else(|| resolved.poll_interval());
+            Ok((resolved, poll_interval))
+        } else {
+            let poll_interval = spec.poll_interval();
+            Ok((spec, poll_interval))
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn process_update<'a, F>(
         store: &mut F,
         index: &mut Index,
+        attempts: &mut Attempts,
         application: &str,
         device: &str,
+        component: Option<&ComponentUpdate<'_>>,
         status: &'a Status<'a>,
+        delivery: &Delivery,
         params: &F::Params,
     ) -> Result<SerializedCommand, anyhow::Error>
     where
         F: FirmwareStore,
     {
-        match store.fetch_metadata(params).await {
+        match telemetry::traced(
+            "FirmwareStore::fetch_metadata",
+            telemetry::device_attributes(application, device),
+            store.fetch_metadata(params),
+        )
+        .await
+        {
             Ok((ctx, Some(metadata))) => {
                 // Update firmware status
                 if let Err(e) = index
-                    .update_status(application, device, &status, Ok(&metadata))
+                    .update_status(
+                        application,
+                        device,
+                        component.map(|c| &c.component),
+                        status,
+                        Ok(&metadata),
+                    )
                     .await
                 {
                     log::warn!(
@@ -123,22 +545,42 @@ impl Updater {
 
                 log::debug!("Got metadata: {:?}", metadata);
 
+                if let Some(Verification::Rejected(reason)) = &metadata.verification {
+                    return Err(anyhow!(
+                        "Firmware for device {}/{} rejected: {}",
+                        application,
+                        device,
+                        reason
+                    ));
+                }
+
+                let key = (
+                    application.to_string(),
+                    device.to_string(),
+                    component.map(|c| c.component.name.to_string()),
+                );
                 if status.version == metadata.version {
+                    attempts.pop(&key);
                     // Don't let this fail us
-                    let _ = store.mark_synced(params, &ctx, true).await;
-                    Ok(
-                        Command::new_sync(&status.version.as_ref(), None, status.correlation_id)
-                            .try_into()?,
+                    let _ = telemetry::traced(
+                        "FirmwareStore::mark_synced",
+                        telemetry::device_attributes(application, device),
+                        store.mark_synced(params, &ctx, true),
                     )
+                    .await;
+                    Ok(Command::new_sync(
+                        status.version.as_ref(),
+                        delivery.poll,
+                        status.correlation_id,
+                    )
+                    .try_into()?)
                 } else {
                     let mut offset = 0;
-                    let mut mtu = 512;
-                    if let Some(m) = status.mtu {
-                        mtu = m as usize;
-                    }
+                    let mut resumed = None;
                     if let Some(update) = &status.update {
                         if update.version == metadata.version {
                             offset = update.offset as usize;
+                            resumed = Some(update.offset);
                         }
                     } else {
                         log::info!(
@@ -150,12 +592,185 @@ impl Updater {
                         );
                     }
 
-                    let _ = store
-                        .update_progress(params, &ctx, offset as u32, metadata.size)
-                        .await;
+                    if attempts
+                        .peek(&key)
+                        .map(|a| a.version != metadata.version)
+                        .unwrap_or(true)
+                    {
+                        attempts.put(key.clone(), Attempt::new(&metadata.version));
+                    }
+                    let attempt = attempts.get_mut(&key).unwrap();
+                    let version = String::from_utf8_lossy(&metadata.version);
+                    if attempt.failures >= delivery.budget {
+                        // Given up on this version, only a different version is offered again
+                        return Ok(Command::new_wait(
+                            store.get_backoff(&ctx).or(delivery.poll),
+                            status.correlation_id,
+                        )
+                        .try_into()?);
+                    }
+                    let details = match attempt.observe(resumed, metadata.size) {
+                        Progress::Failed(failure) => {
+                            let reason = match failure {
+                                Failure::Reverted => format!(
+                                    "Device reverted to version {} after installing version {}",
+                                    String::from_utf8_lossy(status.version.as_ref()),
+                                    version
+                                ),
+                                Failure::Rejected => format!(
+                                    "Device did not install version {}, checksum verification failed",
+                                    version
+                                ),
+                            };
+                            log::warn!(
+                                "Update of device {}/{} failed ({} of {}): {}",
+                                application,
+                                device,
+                                attempt.failures,
+                                delivery.budget,
+                                reason
+                            );
+                            let _ = telemetry::traced(
+                                "FirmwareStore::mark_failed",
+                                telemetry::device_attributes(application, device),
+                                store.mark_failed(params, &ctx, &reason),
+                            )
+                            .await;
+                            if attempt.failures >= delivery.budget {
+                                if let Err(e) = index
+                                    .set_update_failed(application, device, Some(reason))
+                                    .await
+                                {
+                                    log::warn!(
+                                        "Error updating status of device {}/{}: {:?}",
+                                        application,
+                                        device,
+                                        e
+                                    );
+                                }
+                            }
+                            return Ok(Command::new_wait(
+                                store.get_backoff(&ctx).or(delivery.poll),
+                                status.correlation_id,
+                            )
+                            .try_into()?);
+                        }
+                        Progress::Restarted { sent, resumed } => {
+                            // Lost blocks, or a late status, the transfer goes on from where the device is
+                            log::info!(
+                                "Device {}/{} resumed at offset {} after block at offset {}",
+                                application,
+                                device,
+                                resumed,
+                                sent
+                            );
+                            format!(
+                                "Resuming download of version {} at offset {}",
+                                version, resumed
+                            )
+                        }
+                        Progress::Retry(retries) if offset < metadata.size as usize => {
+                            format!("Retrying block at offset {} (retry {})", offset, retries)
+                        }
+                        Progress::Retry(retries) => {
+                            format!("Retrying installation (retry {})", retries)
+                        }
+                        Progress::Proceeding if offset < metadata.size as usize => {
+                            format!("Downloading version {}", version)
+                        }
+                        Progress::Proceeding => format!("Installing version {}", version),
+                    };
+
+                    let _ = telemetry::traced(
+                        "FirmwareStore::update_progress",
+                        telemetry::device_attributes(application, device),
+                        store.update_progress(params, &ctx, offset as u32, metadata.size, &details),
+                    )
+                    .await;
+
+                    if offset < metadata.size as usize && store.hold_download(&ctx) {
+                        log::info!(
+                            "Holding download of {}/{} as requested by firmware store",
+                            application,
+                            device
+                        );
+                        Ok(Command::new_wait(
+                            store.get_backoff(&ctx).or(delivery.poll),
+                            status.correlation_id,
+                        )
+                        .try_into()?)
+                    } else if offset < metadata.size as usize {
+                        let firmware = telemetry::traced(
+                            "FirmwareStore::fetch_firmware",
+                            telemetry::device_attributes(application, device),
+                            store.fetch_firmware(params, &ctx, &metadata),
+                        )
+                        .await
+                        .and_then(|firmware| {
+                            if firmware.len() == metadata.size as usize {
+                                Ok(firmware)
+                            } else {
+                                Err(anyhow!(
+                                    "Firmware is {} bytes, expected {}",
+                                    firmware.len(),
+                                    metadata.size
+                                ))
+                            }
+                        });
+                        let firmware = match firmware {
+                            Ok(firmware) => firmware,
+                            Err(e) => {
+                                // Retried with backoff, the update only fails once out of retries
+                                let reason = format!("Transfer error, {}", e);
+                                let _ = if delivery.last_try {
+                                    telemetry::traced(
+                                        "FirmwareStore::mark_failed",
+                                        telemetry::device_attributes(application, device),
+                                        store.mark_failed(params, &ctx, &reason),
+                                    )
+                                    .await
+                                } else {
+                                    telemetry::traced(
+                                        "FirmwareStore::update_progress",
+                                        telemetry::device_attributes(application, device),
+                                        store.update_progress(
+                                            params,
+                                            &ctx,
+                                            offset as u32,
+                                            metadata.size,
+                                            &format!("{}, retrying", reason),
+                                        ),
+                                    )
+                                    .await
+                                };
+                                return Err(e);
+                            }
+                        };
 
-                    if offset < metadata.size as usize {
-                        let firmware = store.fetch_firmware(params, &ctx, &metadata).await?;
+                        // Blocks must fit into a single command on the transport to the device
+                        let mut mtu = status.mtu.map(|m| m as usize).unwrap_or(DEFAULT_BLOCK_SIZE);
+                        if let Some(max_payload) = delivery.max_payload {
+                            let filler = vec![0xFF; mtu];
+                            mtu = transport::block_size(max_payload, mtu, |size| {
+                                SerializedCommand::try_from(Command::new_write(
+                                    &metadata.version,
+                                    offset as u32,
+                                    &filler[..size],
+                                    status.correlation_id,
+                                ))
+                                .map(|command| command.data.len())
+                                .unwrap_or(usize::MAX)
+                            });
+                            if mtu == 0 {
+                                return Err(anyhow!(
+                                    "Write command for device {}/{} does not fit into {} bytes",
+                                    application,
+                                    device,
+                                    max_payload
+                                ));
+                            }
+                        }
+                        let mtu = attempt.block_size(mtu);
 
                         let to_copy = core::cmp::min(firmware.len() - offset, mtu);
                         let block = &firmware[offset..offset + to_copy];
@@ -165,6 +780,7 @@ impl Updater {
                             offset,
                             block.len()
                         );
+                        attempt.sent_block(offset as u32);
                         Ok(Command::new_write(
                             &metadata.version,
                             offset as u32,
@@ -172,13 +788,31 @@ impl Updater {
                             status.correlation_id,
                         )
                         .try_into()?)
+                    } else if component.map(|c| c.hold_swap).unwrap_or(false) {
+                        log::info!(
+                            "Holding swap of {}/{} until previous components are in sync",
+                            application,
+                            device
+                        );
+                        Ok(Command::new_wait(delivery.poll, status.correlation_id).try_into()?)
+                    } else if store.hold_swap(&ctx) {
+                        log::info!(
+                            "Holding swap of {}/{} as requested by firmware store",
+                            application,
+                            device
+                        );
+                        Ok(Command::new_wait(
+                            store.get_backoff(&ctx).or(delivery.poll),
+                            status.correlation_id,
+                        )
+                        .try_into()?)
                     } else {
-                        let data = hex::decode(&metadata.checksum.trim_start_matches("sha256:"))
-                            .map_err(|e| {
+                        let data = hex::decode(metadata.checksum.trim_start_matches("sha256:"))
+                            .inspect_err(|&e| {
                                 log::warn!("Error decoding hex: {:?}", e);
-                                e
                             })?;
                         log::info!("Sending swap instruction back to device!");
+                        attempt.sent_swap();
                         Ok(
                             Command::new_swap(&metadata.version, &data, status.correlation_id)
                                 .try_into()?,
@@ -186,13 +820,33 @@ impl Updater {
                     }
                 }
             }
+            Ok((ctx, None)) if store.is_cancelled(&ctx) => {
+                // Stop any ongoing update, keeping the current firmware of the device
+                log::info!("Update of device {}/{} cancelled", application, device);
+                Ok(Command::new_sync(
+                    status.version.as_ref(),
+                    delivery.poll,
+                    status.correlation_id,
+                )
+                .try_into()?)
+            }
             Ok((ctx, None)) => {
                 // Don't update status, just ask device to wait
-                Ok(Command::new_wait(store.get_backoff(&ctx), status.correlation_id).try_into()?)
+                Ok(Command::new_wait(
+                    store.get_backoff(&ctx).or(delivery.poll),
+                    status.correlation_id,
+                )
+                .try_into()?)
             }
             Err(e) => {
                 if let Err(e) = index
-                    .update_status(application, device, &status, Err(e.to_string()))
+                    .update_status(
+                        application,
+                        device,
+                        component.map(|c| &c.component),
+                        status,
+                        Err(e.to_string()),
+                    )
                     .await
                 {
                     log::warn!(
@@ -202,10 +856,151 @@ impl Updater {
                         e
                     );
                 }
-                Err(e.into())
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Options for the commands sent to a device.
+struct Delivery {
+    /// Poll interval for sync and wait commands
+    poll: Option<u32>,
+    /// Largest command the transport to the device can deliver, in bytes
+    max_payload: Option<usize>,
+    /// Whether an error exhausts the retry budget of the device, failing the update
+    last_try: bool,
+    /// Failed installations reported by the device before giving up on a version
+    budget: u32,
+}
+
+/// Smallest block size when shrinking blocks after retries.
+const MIN_BLOCK_SIZE: usize = 16;
+
+/// Largest number of times the block size is halved.
+const MAX_SHRINK: u32 = 5;
+
+/// Blocks received in a row before growing the block size again.
+const GROW_AFTER: u32 = 8;
+
+/// Update of a device in progress, used to detect failures the device does not report itself.
+struct Attempt {
+    version: Vec<u8>,
+    /// Offset of the last block sent
+    offset: Option<u32>,
+    /// Whether the swap was sent
+    swapped: bool,
+    /// Retries of the last block or swap
+    retries: u32,
+    /// Number of times the block size was halved after retransmitted blocks
+    shrink: u32,
+    /// Blocks received in a row since the block size last changed
+    received: u32,
+    /// Installations of the version the device reported as failed
+    failures: u32,
+}
+
+#[derive(Debug, PartialEq)]
+enum Progress {
+    Proceeding,
+    Retry(u32),
+    /// Device resumed before blocks that were sent, having lost them or sent a late status
+    Restarted {
+        sent: u32,
+        resumed: u32,
+    },
+    Failed(Failure),
+}
+
+/// Failure of an update reported by the device, which retrying does not fix.
+#[derive(Debug, PartialEq)]
+enum Failure {
+    /// Device is back on its previous version after the swap
+    Reverted,
+    /// Device discarded the firmware instead of swapping
+    Rejected,
+}
+
+impl Attempt {
+    fn new(version: &[u8]) -> Self {
+        Self {
+            version: version.to_vec(),
+            offset: None,
+            swapped: false,
+            retries: 0,
+            shrink: 0,
+            received: 0,
+            failures: 0,
+        }
+    }
+
+    /// Compare the offset the device resumes the update from with what was last sent.
+    fn observe(&mut self, resumed: Option<u32>, size: u32) -> Progress {
+        let progress = match (self.swapped, resumed, self.offset) {
+            (true, None, _) => Some(Progress::Failed(Failure::Reverted)),
+            (true, Some(resumed), _) if resumed < size => Some(Progress::Failed(Failure::Rejected)),
+            (false, Some(resumed), Some(sent)) if resumed < sent => {
+                Some(Progress::Restarted { sent, resumed })
+            }
+            (false, None, Some(sent)) => Some(Progress::Restarted { sent, resumed: 0 }),
+            _ => None,
+        };
+        if let Some(progress) = progress {
+            // Keep the block size, the transport to the device did not change
+            *self = Self {
+                shrink: self.shrink,
+                failures: self.failures + matches!(progress, Progress::Failed(_)) as u32,
+                ..Self::new(&self.version)
+            };
+            return progress;
+        }
+
+        let retry = match (self.swapped, resumed, self.offset) {
+            (true, Some(_), _) => true,
+            (false, Some(resumed), Some(sent)) if resumed == sent => {
+                // Block lost on the way to the device, try smaller blocks
+                self.shrink = (self.shrink + 1).min(MAX_SHRINK);
+                self.received = 0;
+                true
+            }
+            (false, Some(_), Some(_)) => {
+                self.received += 1;
+                if self.received >= GROW_AFTER && self.shrink > 0 {
+                    self.shrink -= 1;
+                    self.received = 0;
+                }
+                false
             }
+            _ => false,
+        };
+        if retry {
+            self.retries += 1;
+            Progress::Retry(self.retries)
+        } else {
+            self.retries = 0;
+            Progress::Proceeding
         }
     }
+
+    /// Block size adapted to retransmitted blocks, up to `max`.
+    fn block_size(&self, max: usize) -> usize {
+        (max >> self.shrink).max(MIN_BLOCK_SIZE).min(max)
+    }
+
+    fn sent_block(&mut self, offset: u32) {
+        self.offset = Some(offset);
+    }
+
+    fn sent_swap(&mut self) {
+        self.swapped = true;
+    }
+}
+
+/// Update of one component of a device with multiple firmware components.
+struct ComponentUpdate<'a> {
+    component: Component<'a>,
+    /// Hold the swap until the previous components are in sync
+    hold_swap: bool,
 }
 
 #[derive(Debug)]
@@ -240,12 +1035,28 @@ pub trait FirmwareStore {
         None
     }
 
+    /// Whether the store asks to hold sending the firmware to the device.
+    fn hold_download(&self, _: &Self::Context) -> bool {
+        false
+    }
+
+    /// Whether the store asks to hold installing firmware sent to the device.
+    fn hold_swap(&self, _: &Self::Context) -> bool {
+        false
+    }
+
+    /// Whether an ongoing update was cancelled in the store.
+    fn is_cancelled(&self, _: &Self::Context) -> bool {
+        false
+    }
+
     async fn update_progress(
         &mut self,
         params: &Self::Params,
         context: &Self::Context,
         offset: u32,
         size: u32,
+        details: &str,
     ) -> Result<(), anyhow::Error>;
 
     async fn mark_synced(
@@ -255,6 +1066,14 @@ pub trait FirmwareStore {
         success: bool,
     ) -> Result<(), anyhow::Error>;
 
+    /// Report a failed update of the device.
+    async fn mark_failed(
+        &mut self,
+        params: &Self::Params,
+        context: &Self::Context,
+        reason: &str,
+    ) -> Result<(), anyhow::Error>;
+
     type Context;
     async fn fetch_firmware(
         &mut self,
@@ -263,3 +1082,133 @@ pub trait FirmwareStore {
         metadata: &Metadata,
     ) -> Result<Vec<u8>, anyhow::Error>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_jitter() {
+        let policy = PollPolicy {
+            interval: Some(100),
+            jitter: 0.1,
+        };
+        for _ in 0..100 {
+            let interval = policy.interval(None).unwrap();
+            assert!((90..=110).contains(&interval));
+        }
+        let interval = policy.interval(Some(1000)).unwrap();
+        assert!((900..=1100).contains(&interval));
+
+        let policy = PollPolicy {
+            interval: None,
+            jitter: 0.0,
+        };
+        assert_eq!(None, policy.interval(None));
+        assert_eq!(Some(30), policy.interval(Some(30)));
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(60),
+            budget: 3,
+            devices_max: 100,
+        };
+        assert_eq!(Duration::from_secs(5), policy.backoff(1));
+        assert_eq!(Duration::from_secs(10), policy.backoff(2));
+        assert_eq!(Duration::from_secs(40), policy.backoff(4));
+        assert_eq!(Duration::from_secs(60), policy.backoff(5));
+        assert_eq!(Duration::from_secs(60), policy.backoff(100));
+    }
+
+    #[test]
+    fn attempt_progress() {
+        let mut attempt = Attempt::new(b"2.0.0");
+        assert_eq!(Progress::Proceeding, attempt.observe(None, 1024));
+        attempt.sent_block(0);
+        assert_eq!(Progress::Proceeding, attempt.observe(Some(512), 1024));
+        attempt.sent_block(512);
+
+        // Block lost on the way to the device
+        assert_eq!(Progress::Retry(1), attempt.observe(Some(512), 1024));
+        assert_eq!(Progress::Retry(2), attempt.observe(Some(512), 1024));
+        assert_eq!(Progress::Proceeding, attempt.observe(Some(1024), 1024));
+
+        attempt.sent_swap();
+        assert_eq!(Progress::Retry(1), attempt.observe(Some(1024), 1024));
+        assert_eq!(
+            Progress::Failed(Failure::Reverted),
+            attempt.observe(None, 1024)
+        );
+
+        // Starts over after a failure
+        assert_eq!(Progress::Proceeding, attempt.observe(None, 1024));
+        attempt.sent_block(512);
+        attempt.sent_swap();
+        assert_eq!(
+            Progress::Failed(Failure::Rejected),
+            attempt.observe(Some(0), 1024)
+        );
+        assert_eq!(2, attempt.failures);
+
+        attempt.sent_block(512);
+        assert_eq!(
+            Progress::Restarted {
+                sent: 512,
+                resumed: 0
+            },
+            attempt.observe(Some(0), 1024)
+        );
+        attempt.sent_block(512);
+        assert_eq!(
+            Progress::Restarted {
+                sent: 512,
+                resumed: 0
+            },
+            attempt.observe(None, 1024)
+        );
+        assert_eq!(2, attempt.failures);
+    }
+
+    #[test]
+    fn attempt_block_size() {
+        let mut attempt = Attempt::new(b"2.0.0");
+        attempt.observe(None, 4096);
+        assert_eq!(256, attempt.block_size(256));
+
+        // Halved for every retransmitted block
+        attempt.sent_block(0);
+        assert_eq!(Progress::Retry(1), attempt.observe(Some(0), 4096));
+        assert_eq!(128, attempt.block_size(256));
+        assert_eq!(Progress::Retry(2), attempt.observe(Some(0), 4096));
+        assert_eq!(64, attempt.block_size(256));
+        for _ in 0..10 {
+            attempt.observe(Some(0), 4096);
+        }
+        assert_eq!(MIN_BLOCK_SIZE, attempt.block_size(256));
+        assert_eq!(32, attempt.block_size(1024));
+        assert_eq!(8, attempt.block_size(8));
+
+        // Grows again after blocks are received
+        let mut offset = 0;
+        for _ in 0..GROW_AFTER {
+            attempt.sent_block(offset);
+            offset += 16;
+            assert_eq!(Progress::Proceeding, attempt.observe(Some(offset), 4096));
+        }
+        assert_eq!(64, attempt.block_size(1024));
+
+        // Kept after a failure
+        attempt.sent_block(offset);
+        assert_eq!(
+            Progress::Restarted {
+                sent: offset,
+                resumed: 0
+            },
+            attempt.observe(Some(0), 4096)
+        );
+        assert_eq!(64, attempt.block_size(1024));
+    }
+}
@@ -0,0 +1,116 @@
+//! Checks of values taken from device and application specs, which anyone editing them controls.
+
+use anyhow::anyhow;
+use reqwest::Url;
+use std::path::{Component, Path};
+
+/// Accept only a single file name, such as the name of a secret or a firmware.
+pub fn file_name(name: &str) -> Result<&str, anyhow::Error> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(n)), None) if n == name => Ok(name),
+        _ => Err(anyhow!("Invalid name '{}', expected a file name", name)),
+    }
+}
+
+/// Accept only a single URL path segment of unreserved characters, such as a Hawkbit tenant.
+pub fn path_segment(segment: &str) -> Result<&str, anyhow::Error> {
+    let unreserved = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~');
+    if segment.is_empty() || segment == "." || segment == ".." || !segment.chars().all(unreserved) {
+        return Err(anyhow!(
+            "Invalid value '{}', expected letters, digits, '-', '.', '_' or '~'",
+            segment
+        ));
+    }
+    Ok(segment)
+}
+
+/// Check if a URL is below one of the given URL prefixes.
+///
+/// Scheme, host and port must match, and the path of the URL must be within the path of the prefix.
+pub fn url_within(url: &str, prefixes: &[String]) -> bool {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    prefixes.iter().filter_map(|p| Url::parse(p).ok()).any(|p| {
+        let path = p.path().trim_end_matches('/');
+        url.scheme() == p.scheme()
+            && url.host_str() == p.host_str()
+            && url.port_or_known_default() == p.port_or_known_default()
+            && url.username() == p.username()
+            && url.path().starts_with(path)
+            && matches!(url.path()[path.len()..].chars().next(), None | Some('/'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names() {
+        assert!(file_name("token").is_ok());
+        assert!(file_name("1.0.0").is_ok());
+        for name in [
+            "",
+            ".",
+            "..",
+            "../token",
+            "a/b",
+            "/etc/passwd",
+            "token/",
+            "./token",
+        ] {
+            assert!(file_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn path_segments() {
+        assert!(path_segment("DEFAULT").is_ok());
+        assert!(path_segment("dev-1.a_b~c").is_ok());
+        for segment in ["", ".", "..", "a/b", "a?b", "a#b", "a%2F", "a b", "ä"] {
+            assert!(path_segment(segment).is_err(), "{}", segment);
+        }
+    }
+
+    #[test]
+    fn url_prefixes() {
+        let prefixes = vec![
+            "https://example.com/releases/".to_string(),
+            "https://cdn.example.com".to_string(),
+        ];
+        assert!(url_within("https://example.com/releases/fw.bin", &prefixes));
+        assert!(url_within(
+            "https://example.com:443/releases/a/fw.bin",
+            &prefixes
+        ));
+        assert!(url_within("https://cdn.example.com/fw.bin", &prefixes));
+
+        assert!(!url_within("http://example.com/releases/fw.bin", &prefixes));
+        assert!(!url_within(
+            "https://example.com/releases-evil/fw.bin",
+            &prefixes
+        ));
+        assert!(!url_within("https://example.com/fw.bin", &prefixes));
+        assert!(!url_within(
+            "https://example.com/releases/../fw.bin",
+            &prefixes
+        ));
+        assert!(!url_within(
+            "https://cdn.example.com.evil.com/fw.bin",
+            &prefixes
+        ));
+        assert!(!url_within(
+            "https://cdn.example.com:8443/fw.bin",
+            &prefixes
+        ));
+        assert!(!url_within(
+            "https://user@cdn.example.com/fw.bin",
+            &prefixes
+        ));
+        assert!(!url_within("not a url", &prefixes));
+        assert!(!url_within("https://example.com/releases/fw.bin", &[]));
+    }
+}